@@ -0,0 +1,164 @@
+-- SPDX-FileCopyrightText: 2022 perillamint
+--
+-- SPDX-License-Identifier: MPL-2.0
+
+-- Wireshark dissector for FZ RPC frames captured by flipperbridge (`PcapngWriter`).
+-- Packets are PB.Main bodies on LINKTYPE_USER0 (DLT 147).
+--
+-- Install: copy into the Wireshark personal Lua plugins directory
+-- (Help -> About Wireshark -> Folders), e.g. ~/.local/lib/wireshark/plugins/.
+--
+-- Header fields and the content type are decoded here. If Wireshark's protobuf
+-- dissector knows flipperzero-protobuf (Preferences -> Protocols -> ProtoBuf ->
+-- search paths), the whole message is decoded as well.
+
+local flipper = Proto("flipper_rpc", "Flipper Zero RPC")
+
+local command_status = {
+    [0] = "OK",
+    [1] = "ERROR",
+    [2] = "ERROR_DECODE",
+    [3] = "ERROR_NOT_IMPLEMENTED",
+    [4] = "ERROR_BUSY",
+    [5] = "ERROR_STORAGE_NOT_READY",
+    [6] = "ERROR_STORAGE_EXIST",
+    [7] = "ERROR_STORAGE_NOT_EXIST",
+    [8] = "ERROR_STORAGE_INVALID_PARAMETER",
+    [9] = "ERROR_STORAGE_DENIED",
+    [10] = "ERROR_STORAGE_INVALID_NAME",
+    [11] = "ERROR_STORAGE_INTERNAL",
+    [12] = "ERROR_STORAGE_NOT_IMPLEMENTED",
+    [13] = "ERROR_STORAGE_ALREADY_OPEN",
+    [14] = "ERROR_CONTINUOUS_COMMAND_INTERRUPTED",
+    [15] = "ERROR_INVALID_PARAMETERS",
+}
+
+-- PB.Main `content` oneof, by field number.
+local content_type = {
+    [4] = "empty",
+    [5] = "system_ping_request",
+    [6] = "system_ping_response",
+    [7] = "storage_list_request",
+    [8] = "storage_list_response",
+    [9] = "storage_read_request",
+    [10] = "storage_read_response",
+    [11] = "storage_write_request",
+    [12] = "storage_delete_request",
+    [13] = "storage_mkdir_request",
+    [14] = "storage_md5sum_request",
+    [15] = "storage_md5sum_response",
+    [16] = "app_start_request",
+    [17] = "app_lock_status_request",
+    [18] = "app_lock_status_response",
+    [19] = "stop_session",
+    [20] = "gui_start_screen_stream_request",
+    [21] = "gui_stop_screen_stream_request",
+    [22] = "gui_screen_frame",
+    [23] = "gui_send_input_event_request",
+    [24] = "storage_stat_request",
+    [25] = "storage_stat_response",
+    [26] = "gui_start_virtual_display_request",
+    [27] = "gui_stop_virtual_display_request",
+    [28] = "storage_info_request",
+    [29] = "storage_info_response",
+    [30] = "storage_rename_request",
+    [31] = "system_reboot_request",
+    [32] = "system_device_info_request",
+    [33] = "system_device_info_response",
+}
+
+local f_command_id = ProtoField.uint32("flipper_rpc.command_id", "Command ID")
+local f_command_status = ProtoField.uint32("flipper_rpc.command_status", "Command status", base.DEC, command_status)
+local f_has_next = ProtoField.bool("flipper_rpc.has_next", "Has next")
+local f_content = ProtoField.uint32("flipper_rpc.content", "Content", base.DEC, content_type)
+local f_content_data = ProtoField.bytes("flipper_rpc.content_data", "Content data")
+flipper.fields = { f_command_id, f_command_status, f_has_next, f_content, f_content_data }
+
+-- Older Wireshark has no protobuf dissector, Dissector.get raises then.
+local has_protobuf, protobuf = pcall(Dissector.get, "protobuf")
+
+-- Decode varint at offset. Returns value and length, or nil if truncated.
+local function varint(tvb, offset)
+    local value = 0
+    local scale = 1
+    local pos = offset
+    while pos < tvb:len() do
+        local byte = tvb(pos, 1):uint()
+        value = value + (byte % 0x80) * scale
+        pos = pos + 1
+        if byte < 0x80 then
+            return value, pos - offset
+        end
+        scale = scale * 0x80
+    end
+    return nil
+end
+
+function flipper.dissector(tvb, pinfo, tree)
+    pinfo.cols.protocol = "FLIPPER RPC"
+    local subtree = tree:add(flipper, tvb())
+    local summary = {}
+
+    local pos = 0
+    while pos < tvb:len() do
+        local tag, tag_len = varint(tvb, pos)
+        if tag == nil then
+            subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Truncated tag")
+            break
+        end
+        local field = math.floor(tag / 8)
+        local wire = tag % 8
+        local start = pos
+        pos = pos + tag_len
+
+        if wire == 0 then
+            local value, len = varint(tvb, pos)
+            if value == nil then
+                subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Truncated varint")
+                break
+            end
+            pos = pos + len
+            local range = tvb(start, pos - start)
+            if field == 1 then
+                subtree:add(f_command_id, range, value)
+                table.insert(summary, "id=" .. value)
+            elseif field == 2 then
+                subtree:add(f_command_status, range, value)
+                if value ~= 0 then
+                    table.insert(summary, command_status[value] or ("status=" .. value))
+                end
+            elseif field == 3 then
+                subtree:add(f_has_next, range, value ~= 0)
+                if value ~= 0 then
+                    table.insert(summary, "has_next")
+                end
+            end
+        elseif wire == 2 then
+            local len, len_len = varint(tvb, pos)
+            if len == nil or pos + len_len + len > tvb:len() then
+                subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Truncated content")
+                break
+            end
+            local item = subtree:add(f_content, tvb(start, pos + len_len - start), field)
+            if len > 0 then
+                item:add(f_content_data, tvb(pos + len_len, len))
+            end
+            table.insert(summary, 1, content_type[field] or ("content " .. field))
+            pos = pos + len_len + len
+        else
+            subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Unexpected wire type " .. wire)
+            break
+        end
+    end
+
+    -- p2p_dir comes from pcapng epb_flags: 0 is outbound (sent), 1 is inbound.
+    local direction = pinfo.p2p_dir == 0 and "Host -> FZ" or "FZ -> Host"
+    pinfo.cols.info = direction .. ": " .. table.concat(summary, ", ")
+
+    if has_protobuf then
+        pinfo.private["pb_msg_type"] = "message,PB.Main"
+        pcall(Dissector.call, protobuf, tvb, pinfo, tree)
+    end
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, flipper)
@@ -8,17 +8,22 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-mod codec;
-mod consts;
-mod error;
-mod transport;
-
 use async_lock::RwLock;
+use flipper_bridge::cli::{CliSession, LogLevel};
+use flipper_bridge::error::FlipperError;
+use flipper_bridge::locator::DeviceLocator;
+use flipper_bridge::transport::ble::{
+    BTLETransport, BlePeripheral, FlipperScanner, SearchCriteria,
+};
+use flipper_bridge::transport::pcapng::PcapngWriter;
+use flipper_bridge::transport::serial::SerialTransport;
+use flipper_bridge::transport::stdio::StdioTransport;
+use flipper_bridge::transport::tap::tap_channel;
+use flipper_bridge::transport::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport};
+use futures::stream::StreamExt;
 use pretty_hex::*;
 use std::sync::Arc;
-use transport::ble::{BTLETransport, FlipperScanner};
-use transport::serial::SerialTransport;
-use transport::FlipperTransport;
+use std::time::Duration;
 
 use clap::Parser;
 
@@ -30,6 +35,34 @@ extern crate lazy_static;
 struct Args {
     #[clap(long, short = 't', value_name = "TRANSPORT`")]
     transport: String,
+    /// Serial port. Auto-detected if omitted.
+    #[clap(long, short = 'p', value_name = "TTY")]
+    port: Option<String>,
+    /// Flipper name. `auto` transport picks USB or BLE by it, `ble` the device to connect.
+    #[clap(long, short = 'n', value_name = "NAME")]
+    name: Option<String>,
+    /// Record piped frames into pcapng file, see contrib/wireshark.
+    #[clap(long, value_name = "FILE")]
+    capture: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Relay raw FZ RPC frames between stdin/stdout and the device.
+    Pipe,
+    /// Run FZShell command over serial and print its output.
+    Shell {
+        #[clap(required = true)]
+        command: Vec<String>,
+    },
+    /// Tail device log over serial into host log, until Ctrl-C.
+    /// Use RUST_LOG=flipper=trace to see it.
+    Log {
+        /// One of error, warn, info, debug, trace.
+        level: Option<String>,
+    },
 }
 
 lazy_static! {
@@ -39,6 +72,22 @@ lazy_static! {
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    match &ARGS.command {
+        Some(Command::Pipe) => {
+            pipe().await;
+            return;
+        }
+        Some(Command::Shell { command }) => {
+            shell(&command.join(" ")).await;
+            return;
+        }
+        Some(Command::Log { level }) => {
+            tail_log(level.as_deref()).await;
+            return;
+        }
+        None => {}
+    }
+
     match ARGS.transport.as_str() {
         "ble" => {
             btle_example().await;
@@ -52,8 +101,23 @@ async fn main() {
     }
 }
 
+/// Serial port from arguments, or the first Flipper found on USB.
+fn serial_port() -> String {
+    if let Some(port) = &ARGS.port {
+        return port.clone();
+    }
+
+    let device = SerialTransport::discover()
+        .unwrap()
+        .into_iter()
+        .next()
+        .expect("No Flipper found on USB.");
+    log::info!("Using {} at {}", device.name, device.path);
+    device.path
+}
+
 async fn serial_example() {
-    let mut transport = SerialTransport::new("/dev/ttyACM0");
+    let mut transport = SerialTransport::new(&serial_port());
     transport.init().await.unwrap();
 
     let (mut receiver, mut sender) = transport.into_channel();
@@ -69,6 +133,19 @@ async fn serial_example() {
     futures::join!((recv_thread));
 }
 
+/// Scan for the Flipper given by `--name`, or the one with the strongest signal.
+async fn search_flipper(scanner: &FlipperScanner) -> BlePeripheral {
+    let criteria = match &ARGS.name {
+        Some(name) => SearchCriteria::Name(name.clone()),
+        None => SearchCriteria::StrongestSignal,
+    };
+    scanner
+        .search(criteria, Duration::from_secs(5))
+        .await
+        .unwrap()
+        .peripheral
+}
+
 async fn btle_example() {
     let mut scanner = FlipperScanner::new().await.unwrap();
     let adapters = scanner.get_adapter_name().await.unwrap();
@@ -76,7 +153,7 @@ async fn btle_example() {
     // Just use adapter zero.
     scanner.set_adapter(0).unwrap();
     // search it.
-    let flip = scanner.search_flipper_by_name("Flipper ").await.unwrap();
+    let flip = search_flipper(&scanner).await;
     println!("{:?}", flip);
 
     let mut transport = BTLETransport::new(flip).await;
@@ -94,3 +171,81 @@ async fn btle_example() {
 
     futures::join!((recv_thread));
 }
+
+async fn shell(command: &str) {
+    let mut session = CliSession::open(&serial_port()).await.unwrap();
+    println!("{}", session.command(command).await.unwrap());
+}
+
+async fn tail_log(level: Option<&str>) {
+    let level = level.map(|x| LogLevel::from_name(x).expect("Invalid log level."));
+    let mut session = CliSession::open(&serial_port()).await.unwrap();
+    let lines = session.tail_log(level, tokio::signal::ctrl_c());
+    futures::pin_mut!(lines);
+    while let Some(line) = lines.next().await {
+        line.unwrap().log();
+    }
+}
+
+async fn pipe() {
+    // stdout is reserved for RPC frames, so only log from here on.
+    let channel = match ARGS.transport.as_str() {
+        "ble" => {
+            let mut scanner = FlipperScanner::new().await.unwrap();
+            scanner.set_adapter(0).unwrap();
+            let flip = search_flipper(&scanner).await;
+            let mut transport = BTLETransport::new(flip).await;
+            transport.init().await.unwrap();
+            transport.into_channel()
+        }
+        "serial" => {
+            let mut transport = SerialTransport::new(&serial_port());
+            transport.init().await.unwrap();
+            transport.into_channel()
+        }
+        "auto" => {
+            let name = ARGS
+                .name
+                .as_deref()
+                .expect("auto transport requires --name.");
+            let device = DeviceLocator::new().locate(name).await.unwrap();
+            device.connect(Default::default()).await.unwrap()
+        }
+        _ => {
+            eprintln!("Require transport type. Use --help for more information.");
+            return;
+        }
+    };
+    let (mut device_rx, mut device_tx) = match &ARGS.capture {
+        Some(path) => tap_channel(channel, PcapngWriter::create(path).unwrap()),
+        None => channel,
+    };
+
+    let mut host = StdioTransport::new();
+    host.init().await.unwrap();
+    let (mut host_rx, mut host_tx) = host.into_channel();
+
+    let upstream = relay(&mut *host_rx, &mut *device_tx);
+    let downstream = relay(&mut *device_rx, &mut *host_tx);
+    let err = tokio::select! {
+        err = upstream => err,
+        err = downstream => err,
+    };
+    log::info!("Pipe closed: {}", err);
+}
+
+/// Forward frames from receiver to sender until either side fails.
+async fn relay(
+    receiver: &mut (dyn FlipperFrameReceiver + Send + Sync),
+    sender: &mut (dyn FlipperFrameSender + Send + Sync),
+) -> FlipperError {
+    loop {
+        let frame = match receiver.read_frame().await {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        if let Err(e) = sender.write_frame(&frame).await {
+            return e;
+        }
+    }
+}
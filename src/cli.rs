@@ -0,0 +1,455 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::consts::PROMPT_PATTERN;
+use crate::error::{FlipperError, TimeoutPhase};
+use crate::transport::lock::DeviceLock;
+use crate::transport::serial::{open_port, SerialConfig};
+use crate::transport::{with_timeout, TransportConfig};
+use async_stream::try_stream;
+use futures::stream::Stream;
+use log::trace;
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::SerialStream;
+
+use pretty_hex::*;
+
+/// Find subsequence in u8 slice.
+/// Code from https://stackoverflow.com/questions/35901547/how-can-i-find-a-subsequence-in-a-u8-slice
+pub(crate) fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// ETX, what Ctrl-C sends. Stops long running commands like `log`.
+const CTRL_C: u8 = 0x03;
+
+/// Flipper Zero log level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Level name as accepted by `log` command.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    /// Parse level name, e.g. "debug".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    /// Parse level letter as printed in log lines, e.g. "I".
+    fn from_letter(letter: &str) -> Option<Self> {
+        match letter {
+            "E" => Some(LogLevel::Error),
+            "W" => Some(LogLevel::Warn),
+            "I" => Some(LogLevel::Info),
+            "D" => Some(LogLevel::Debug),
+            "T" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+/// Single line of Flipper Zero log, like `12345 [I][BtSrv] Bluetooth started`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLine {
+    /// Device tick count (milliseconds since boot) when the line was logged.
+    pub timestamp: u32,
+    pub level: LogLevel,
+    pub tag: String,
+    pub message: String,
+}
+
+impl LogLine {
+    /// Parse log line. ANSI color codes are ignored.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = strip_ansi(line);
+        let (timestamp, rest) = line.split_once(' ')?;
+        let timestamp = timestamp.parse().ok()?;
+        let (level, rest) = rest.strip_prefix('[')?.split_once(']')?;
+        let level = LogLevel::from_letter(level)?;
+        let (tag, message) = rest.strip_prefix('[')?.split_once(']')?;
+
+        Some(Self {
+            timestamp,
+            level,
+            tag: tag.to_string(),
+            message: message.strip_prefix(' ').unwrap_or(message).to_string(),
+        })
+    }
+
+    /// Re-emit line through the `log` crate, with `flipper::<tag>` as target.
+    pub fn log(&self) {
+        let target = format!("flipper::{}", self.tag);
+        log::log!(target: &target, self.level.into(), "[{}] {}", self.timestamp, self.message);
+    }
+}
+
+/// Remove ANSI escape sequences (CSI only, which is all FZ log uses).
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Text session with FZShell, the Flipper Zero command line.
+///
+/// Commands are written as typed, and their output is everything the device
+/// prints until the next `PROMPT_PATTERN` prompt.
+pub struct CliSession<S> {
+    stream: S,
+    config: TransportConfig,
+    buf: Vec<u8>,
+    _lock: Option<DeviceLock>,
+}
+
+impl CliSession<SerialStream> {
+    /// Lock and open tty, then wait for FZShell prompt.
+    pub async fn open(tty: &str) -> Result<Self, FlipperError> {
+        Self::open_with(tty, &SerialConfig::default()).await
+    }
+
+    /// Same as `open`, with custom serial port settings.
+    pub async fn open_with(tty: &str, serial_config: &SerialConfig) -> Result<Self, FlipperError> {
+        let (port, lock) = open_port(tty, serial_config)?;
+        let mut session = Self::new(port);
+        session._lock = Some(lock);
+        session.init().await?;
+
+        Ok(session)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> CliSession<S> {
+    /// Create CliSession on top of byte stream connected to FZShell.
+    /// Call `init` before running commands.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            config: TransportConfig::default(),
+            buf: vec![],
+            _lock: None,
+        }
+    }
+
+    /// Set timeouts. Handshake timeout applies to waiting for a prompt,
+    /// read timeout to waiting for command output.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Parse `data`, already read off the stream, before reading further.
+    pub fn with_buffered(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Get back the underlying byte stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Ask for a fresh prompt and wait for it.
+    pub async fn init(&mut self) -> Result<(), FlipperError> {
+        self.write_raw(b"\r").await?;
+        self.wait_prompt().await
+    }
+
+    /// Wait until FZShell prompt shows up.
+    pub async fn wait_prompt(&mut self) -> Result<(), FlipperError> {
+        with_timeout(
+            self.config.handshake_timeout,
+            TimeoutPhase::Handshake,
+            self.read_until_pattern(&PROMPT_PATTERN),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Run shell command, like `info` or `storage list /ext`, and return its output.
+    pub async fn command(&mut self, command: &str) -> Result<String, FlipperError> {
+        self.send_command(command).await?;
+        let output = with_timeout(
+            self.config.read_timeout,
+            TimeoutPhase::Read,
+            self.read_until_pattern(&PROMPT_PATTERN),
+        )
+        .await?;
+
+        // Output ends with "\r\n>: ". Prompt is already consumed, trim the line break too.
+        let output = &output[..output.len() - PROMPT_PATTERN.len()];
+        let output = output.strip_suffix(b"\r").unwrap_or(output);
+        Ok(String::from_utf8_lossy(output).into_owned())
+    }
+
+    /// Run `log` command and stream parsed log lines until `stop` resolves,
+    /// e.g. `tokio::signal::ctrl_c()`. Then the command is interrupted with Ctrl-C and
+    /// the stream ends once the prompt is back.
+    ///
+    /// Dropping the stream before that leaves the shell in log mode.
+    pub fn tail_log<'a, F>(
+        &'a mut self,
+        level: Option<LogLevel>,
+        stop: F,
+    ) -> impl Stream<Item = Result<LogLine, FlipperError>> + 'a
+    where
+        F: Future + Send + 'a,
+    {
+        try_stream! {
+            let command = match level {
+                Some(level) => format!("log {}", level.as_str()),
+                None => "log".to_string(),
+            };
+            self.send_command(&command).await?;
+
+            tokio::pin!(stop);
+            loop {
+                let line = tokio::select! {
+                    _ = &mut stop => break,
+                    line = self.read_until_pattern(b"\n") => line,
+                };
+                let line = line?;
+
+                // Skip anything else, like "Press CTRL+C to stop..." banner.
+                if let Some(line) = LogLine::parse(String::from_utf8_lossy(&line).trim_end()) {
+                    yield line;
+                }
+            }
+
+            self.write_raw(&[CTRL_C]).await?;
+            self.wait_prompt().await?;
+        }
+    }
+
+    /// Run `start_rpc_session` and hand back the stream, now speaking FZ RPC frames,
+    /// along with bytes already read past the command echo. These start the first frame
+    /// and must be decoded before anything read from the stream.
+    pub async fn start_rpc_session(mut self) -> Result<(S, Vec<u8>), FlipperError> {
+        with_timeout(
+            self.config.handshake_timeout,
+            TimeoutPhase::Handshake,
+            self.send_command("start_rpc_session"),
+        )
+        .await?;
+
+        Ok((self.stream, self.buf))
+    }
+
+    /// Type command and wait for its echo, which skips any stale output before it.
+    async fn send_command(&mut self, command: &str) -> Result<(), FlipperError> {
+        if command.contains(['\r', '\n']) {
+            return Err(FlipperError::IOFailure(
+                "Command must be a single line.".to_string(),
+            ));
+        }
+
+        self.write_raw(format!("{}\r", command).as_bytes()).await?;
+        self.read_until_pattern(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Write raw bytes async-y to the stream.
+    async fn write_raw(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        trace!("CLI Write - {}", data.hex_dump());
+        self.stream
+            .write_all(data)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        self.stream
+            .flush()
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+
+        Ok(())
+    }
+
+    /// Read stream until specific pattern, like FZShell prompt.
+    /// Returns everything before the pattern, pattern included.
+    async fn read_until_pattern(&mut self, pattern: &[u8]) -> Result<Vec<u8>, FlipperError> {
+        let mut buf = [0u8; 1024];
+        let mut searched = 0;
+
+        loop {
+            if let Some(pos) = find_subsequence(&self.buf[searched..], pattern) {
+                let end = searched + pos + pattern.len();
+                return Ok(self.buf.drain(..end).collect());
+            }
+            searched = self.buf.len().saturating_sub(pattern.len() - 1);
+
+            let readsz = self
+                .stream
+                .read(&mut buf)
+                .await
+                .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+            if readsz == 0 {
+                return Err(FlipperError::Disconnected);
+            }
+            trace!("CLI Read - {}", buf[0..readsz].hex_dump());
+            self.buf.extend_from_slice(&buf[0..readsz]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream::StreamExt;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn command_output_between_echo_and_prompt() {
+        let (host, mut device) = duplex(1024);
+        let mut session = CliSession::new(host);
+
+        let device_task = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            // Stale prompts from the port opening, then answer to the nudge.
+            device.write_all(b"motd\r\n>: \r\n>: ").await.unwrap();
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"\r");
+            device.write_all(b"\r\n>: ").await.unwrap();
+
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"uptime\r");
+            device
+                .write_all(b"uptime\r\nUptime: 0h0m1s\r\n>: ")
+                .await
+                .unwrap();
+        });
+
+        session.init().await.unwrap();
+        assert_eq!(session.command("uptime").await.unwrap(), "Uptime: 0h0m1s");
+        device_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_frame_bytes_after_start_rpc_session() {
+        let (host, mut device) = duplex(1024);
+        let session = CliSession::new(host);
+
+        let device_task = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"start_rpc_session\r");
+            // First frame right behind the echo.
+            device
+                .write_all(b"start_rpc_session\r\n\x02\x08\x01")
+                .await
+                .unwrap();
+        });
+
+        let (_, leftover) = session.start_rpc_session().await.unwrap();
+        assert_eq!(leftover, [0x02, 0x08, 0x01]);
+        device_task.await.unwrap();
+    }
+
+    #[test]
+    fn parse_log_line() {
+        assert_eq!(
+            LogLine::parse("1234 \x1b[32m[I][BtSrv] \x1b[0mBluetooth started"),
+            Some(LogLine {
+                timestamp: 1234,
+                level: LogLevel::Info,
+                tag: "BtSrv".to_string(),
+                message: "Bluetooth started".to_string(),
+            })
+        );
+        assert_eq!(LogLine::parse("Press CTRL+C to stop..."), None);
+    }
+
+    #[tokio::test]
+    async fn tail_log_until_stopped() {
+        let (host, mut device) = duplex(1024);
+        let mut session = CliSession::new(host);
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let device_task = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"log debug\r");
+            device
+                .write_all(b"log debug\r\nPress CTRL+C to stop...\r\n10 [D][Tag] one\r\n")
+                .await
+                .unwrap();
+            device.write_all(b"20 [E][Tag] two\r\n").await.unwrap();
+
+            let n = device.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &[CTRL_C]);
+            device.write_all(b"\r\n>: ").await.unwrap();
+        });
+
+        let lines = session.tail_log(Some(LogLevel::Debug), stop_rx);
+        tokio::pin!(lines);
+        assert_eq!(lines.next().await.unwrap().unwrap().message, "one");
+        assert_eq!(lines.next().await.unwrap().unwrap().level, LogLevel::Error);
+        stop_tx.send(()).unwrap();
+        assert!(lines.next().await.is_none());
+        device_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_multiline_command() {
+        let (host, _device) = duplex(64);
+        let mut session = CliSession::new(host);
+        assert!(session.command("info\rreboot").await.is_err());
+    }
+}
@@ -9,42 +9,139 @@
  */
 
 use crate::consts::MAX_FRAME_LENGTH;
-use bytes::{Buf, BufMut, BytesMut};
+use crate::rpc;
+use crate::transport::FrameStats;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use integer_encoding::VarInt;
+use log::warn;
 use std::io::{Error, ErrorKind, Result};
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Default)]
+/// Longest possible u64 varint.
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// Length-delimited FZ RPC frame codec.
+/// Frames are decoded in place: the caller's buffer must be kept between `decode` calls.
 pub(crate) struct FlipperCodec {
-    buf: Vec<u8>,
+    max_frame_length: usize,
+    resync: bool,
+    /// Currently skipping bytes after a corrupted frame.
+    skipping: bool,
+    stats: FrameStats,
+}
+
+impl Default for FlipperCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_length: MAX_FRAME_LENGTH,
+            resync: false,
+            skipping: false,
+            stats: FrameStats::default(),
+        }
+    }
+}
+
+impl FlipperCodec {
+    /// Largest frame body accepted by `decode` and `encode`.
+    pub(crate) fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    pub(crate) fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    /// Skip corrupted bytes until a valid PB.Main frame instead of failing.
+    pub(crate) fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    pub(crate) fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Header and body length of the frame at the start of `buf`.
+    /// `Ok(None)` if more data is needed to tell.
+    fn frame_bounds(&self, buf: &[u8]) -> Result<Option<(usize, usize)>> {
+        let (len, consumed) = match u64::decode_var(buf) {
+            Some(x) => x,
+            None if buf.len() >= MAX_VARINT_LENGTH => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid frame length!".to_string(),
+                ));
+            }
+            None => return Ok(None),
+        };
+
+        // Check data length sanity
+        if len > self.max_frame_length as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Data too big!".to_string(),
+            ));
+        }
+
+        if buf.len() >= consumed + len as usize {
+            Ok(Some((consumed, len as usize)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Drop one byte of a corrupted frame.
+    fn skip(&mut self, buf: &mut BytesMut) {
+        if !self.skipping {
+            self.skipping = true;
+            self.stats.resyncs += 1;
+        }
+        buf.advance(1);
+        self.stats.discarded_bytes += 1;
+    }
 }
 
 impl Decoder for FlipperCodec {
-    type Item = Vec<u8>;
+    type Item = Bytes;
     type Error = Error;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
-        self.buf.extend_from_slice(buf);
-        buf.advance(buf.len());
-        match u64::decode_var(&self.buf) {
-            Some((len, consumed)) => {
-                // Check data length sanity
-                if len as usize > MAX_FRAME_LENGTH {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Data too big!".to_string(),
-                    ));
-                }
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>> {
+        loop {
+            match self.frame_bounds(buf) {
+                Ok(Some((consumed, len))) => {
+                    // Length prefix alone is too weak a sync marker, check the body as well.
+                    // Empty frame is a valid PB.Main, but device never sends one while
+                    // a stray zero byte is common line noise.
+                    let body = &buf[consumed..consumed + len];
+                    if self.resync && (body.is_empty() || !rpc::is_main(body)) {
+                        self.skip(buf);
+                        continue;
+                    }
+                    if self.skipping {
+                        self.skipping = false;
+                        warn!(
+                            "Resynchronized, {} bytes discarded so far.",
+                            self.stats.discarded_bytes
+                        );
+                    }
 
-                if self.buf.len() >= len as usize + consumed {
                     // Data is ready!
-                    self.buf.drain(0..consumed);
-                    Ok(Some(self.buf.drain(0..len as usize).collect()))
-                } else {
-                    Ok(None)
+                    buf.advance(consumed);
+                    return Ok(Some(buf.split_to(len).freeze()));
+                }
+                Ok(None) => {
+                    if let Some((len, consumed)) = u64::decode_var(buf) {
+                        // Don't stall on garbage length until that many bytes arrive.
+                        if self.resync && !rpc::is_main_prefix(&buf[consumed..]) {
+                            self.skip(buf);
+                            continue;
+                        }
+                        buf.reserve((consumed + len as usize).saturating_sub(buf.len()));
+                    }
+                    return Ok(None);
                 }
+                Err(_) if self.resync => self.skip(buf),
+                Err(e) => return Err(e),
             }
-            None => Ok(None),
         }
     }
 }
@@ -53,10 +150,10 @@ impl Encoder<&[u8]> for FlipperCodec {
     type Error = Error;
 
     fn encode(&mut self, data: &[u8], buf: &mut BytesMut) -> Result<()> {
-        let mut header = [0u8; 8];
+        let mut header = [0u8; MAX_VARINT_LENGTH];
 
         // Check data length sanity
-        if data.len() > MAX_FRAME_LENGTH {
+        if data.len() > self.max_frame_length {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "Data too big!".to_string(),
@@ -85,7 +182,10 @@ mod test {
 
         buf.put_slice(&[0x04, 0x05]);
         let res_2 = codec.decode(&mut buf).unwrap();
-        assert_eq!(res_2, Some(vec![0x01, 0x02, 0x03, 0x04, 0x05]));
+        assert_eq!(
+            res_2,
+            Some(Bytes::from_static(&[0x01, 0x02, 0x03, 0x04, 0x05]))
+        );
     }
 
     #[test]
@@ -100,6 +200,76 @@ mod test {
         }
     }
 
+    /// command_id: 1, ping_response { data: [0xaa] }
+    const PONG: [u8; 8] = [0x07, 0x08, 0x01, 0x32, 0x03, 0x0a, 0x01, 0xaa];
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut codec = FlipperCodec::default();
+        codec.set_resync(true);
+        let mut buf = BytesMut::new();
+        // Shell leftovers, then a corrupted length prefix.
+        buf.put_slice(b"\r\n>: ");
+        buf.put_slice(&[0xFE, 0xFF, 0x03]);
+        for _ in 0..16 {
+            buf.put_slice(&PONG);
+        }
+
+        let mut frames = 0;
+        while let Some(frame) = codec.decode(&mut buf).unwrap() {
+            assert_eq!(frame, PONG[1..]);
+            frames += 1;
+        }
+        assert_eq!(frames, 16);
+        assert_eq!(
+            codec.stats(),
+            FrameStats {
+                discarded_bytes: 8,
+                resyncs: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn resync_keeps_valid_frames() {
+        let mut codec = FlipperCodec::default();
+        codec.set_resync(true);
+        let mut buf = BytesMut::new();
+        buf.put_slice(&PONG[..4]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.put_slice(&PONG[4..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Bytes::copy_from_slice(&PONG[1..]))
+        );
+        assert_eq!(codec.stats(), FrameStats::default());
+    }
+
+    #[test]
+    fn custom_max_frame_length() {
+        let mut codec = FlipperCodec::default();
+        codec.set_max_frame_length(4);
+        let mut buf = BytesMut::new();
+        codec.encode(&[0x01, 0x02, 0x03, 0x04], &mut buf).unwrap();
+        assert!(codec
+            .encode(&[0x01, 0x02, 0x03, 0x04, 0x05], &mut buf)
+            .is_err());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 4);
+
+        buf.put_slice(&[0x05, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        buf.clear();
+        let mut codec = FlipperCodec::default();
+        codec.set_max_frame_length(4096);
+        let large_data = [0x08; 4096];
+        codec.encode(&large_data, &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 4096);
+    }
+
     #[test]
     fn check_basic_build_frame() {
         let mut codec = FlipperCodec::default();
@@ -17,6 +17,10 @@ pub const MAX_FRAME_LENGTH: usize = 1536;
 /// Flipper zero prompt pattern in u8 slice.
 /// Human readable representation: '\n>: '
 pub const PROMPT_PATTERN: [u8; 4] = [0x0a, 0x3e, 0x3a, 0x20];
+/// Flipper Zero USB CDC vendor ID (STMicroelectronics).
+pub const USB_VID: u16 = 0x0483;
+/// Flipper Zero USB CDC product ID (STM32 Virtual COM Port).
+pub const USB_PID: u16 = 0x5740;
 
 lazy_static! {
     /// BLE GATT characteristic UUIDs are originated from
@@ -29,4 +33,12 @@ lazy_static! {
     pub static ref BLE_TX_CHARACTERISTIC_UUID: Uuid = Uuid::parse_str("19ed82ae-ed21-4c9d-4145-228e62fe0000").unwrap();
     /// Flipper Zero Overflow characteristic UUID
     pub static ref BLE_OVERFLOW_CHARACTERISTIC_UUID: Uuid = Uuid::parse_str("19ed82ae-ed21-4c9d-4145-228e63fe0000").unwrap();
+    /// Battery Service, Battery Level characteristic UUID
+    pub static ref BLE_BATTERY_LEVEL_UUID: Uuid = Uuid::parse_str("00002a19-0000-1000-8000-00805f9b34fb").unwrap();
+    /// Device Information Service, Serial Number String characteristic UUID
+    pub static ref BLE_SERIAL_NUMBER_UUID: Uuid = Uuid::parse_str("00002a25-0000-1000-8000-00805f9b34fb").unwrap();
+    /// Device Information Service, Firmware Revision String characteristic UUID
+    pub static ref BLE_FIRMWARE_REVISION_UUID: Uuid = Uuid::parse_str("00002a26-0000-1000-8000-00805f9b34fb").unwrap();
+    /// Device Information Service, Manufacturer Name String characteristic UUID
+    pub static ref BLE_MANUFACTURER_NAME_UUID: Uuid = Uuid::parse_str("00002a29-0000-1000-8000-00805f9b34fb").unwrap();
 }
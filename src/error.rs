@@ -8,8 +8,30 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::fmt;
 use thiserror::Error;
 
+/// Transport phase which can time out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// Transport initialization, e.g. waiting for FZShell prompt.
+    Handshake,
+    /// Reading a frame.
+    Read,
+    /// Writing a frame.
+    Write,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutPhase::Handshake => write!(f, "handshake"),
+            TimeoutPhase::Read => write!(f, "read"),
+            TimeoutPhase::Write => write!(f, "write"),
+        }
+    }
+}
+
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum FlipperError {
     #[error("Failed to fetch adapter list: {0}")]
@@ -24,6 +46,22 @@ pub enum FlipperError {
     DataTooLarge(usize),
     #[error("Index out of bounds.")]
     OutOfBounds,
+    #[error("Timed out during {0}.")]
+    Timeout(TimeoutPhase),
+    #[error("Transport disconnected.")]
+    Disconnected,
+    #[error("Device not found.")]
+    NotFound,
+    #[error("Device is in use{}.", .pid.map(|x| format!(" by pid {}", x)).unwrap_or_default())]
+    DeviceBusy { pid: Option<u32> },
     #[error("Unknown internal error. BAD!")]
     Unknown,
 }
+
+impl FlipperError {
+    /// Whether the failed operation may succeed if retried later,
+    /// e.g. after the transport reconnected.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FlipperError::Timeout(_) | FlipperError::Disconnected)
+    }
+}
@@ -8,11 +8,18 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+/// FZShell text session.
+#[cfg(feature = "serial")]
+pub mod cli;
 /// Flipper Constants.
 pub mod consts;
 /// FlipperBridge error types.
 pub mod error;
+/// Find Flipper Zero by name.
+#[cfg(any(feature = "ble", feature = "serial"))]
+pub mod locator;
 /// FlipperBridge transport.
 pub mod transport;
 
 pub(crate) mod codec;
+pub(crate) mod rpc;
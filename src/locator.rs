@@ -0,0 +1,124 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::FlipperError;
+use crate::transport::{
+    FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, TransportConfig,
+};
+use log::debug;
+use std::time::Duration;
+
+#[cfg(feature = "ble")]
+use crate::transport::ble::{BTLETransport, BlePeripheral, FlipperScanner};
+#[cfg(feature = "serial")]
+use crate::transport::serial::{SerialDevice, SerialTransport};
+#[cfg(feature = "ble")]
+use futures::stream::StreamExt;
+
+/// Flipper Zero resolved by DeviceLocator.
+pub enum LocatedDevice {
+    #[cfg(feature = "serial")]
+    Serial(SerialDevice),
+    #[cfg(feature = "ble")]
+    Ble(BlePeripheral),
+}
+
+impl LocatedDevice {
+    /// Create transport for the device, initialize it and split into receiver / sender pair.
+    pub async fn connect(
+        self,
+        config: TransportConfig,
+    ) -> Result<
+        (
+            Box<dyn FlipperFrameReceiver + Send + Sync>,
+            Box<dyn FlipperFrameSender + Send + Sync>,
+        ),
+        FlipperError,
+    > {
+        match self {
+            #[cfg(feature = "serial")]
+            LocatedDevice::Serial(device) => {
+                let mut transport = SerialTransport::new(&device.path).with_config(config);
+                transport.init().await?;
+                Ok(transport.into_channel())
+            }
+            #[cfg(feature = "ble")]
+            LocatedDevice::Ble(flipper) => {
+                let mut transport = BTLETransport::new(flipper).await.with_config(config);
+                transport.init().await?;
+                Ok(transport.into_channel())
+            }
+        }
+    }
+}
+
+/// Resolve Flipper Zero name, e.g. "Tebirod", into a device on any transport.
+/// USB is preferred when the device is reachable both ways.
+pub struct DeviceLocator {
+    scan_timeout: Duration,
+}
+
+impl Default for DeviceLocator {
+    fn default() -> Self {
+        Self {
+            scan_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl DeviceLocator {
+    /// Create locator which scans BLE for 10s, see `with_scan_timeout`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long to scan BLE for the device.
+    pub fn with_scan_timeout(mut self, scan_timeout: Duration) -> Self {
+        self.scan_timeout = scan_timeout;
+        self
+    }
+
+    /// Find Flipper by name. Returns `FlipperError::NotFound` if it is nowhere to be seen.
+    pub async fn locate(&self, name: &str) -> Result<LocatedDevice, FlipperError> {
+        #[cfg(feature = "serial")]
+        if let Some(device) = SerialTransport::discover()?
+            .into_iter()
+            .find(|device| device.name == name)
+        {
+            debug!("Found {} on USB at {}", name, device.path);
+            return Ok(LocatedDevice::Serial(device));
+        }
+
+        #[cfg(feature = "ble")]
+        if let Some(flipper) = self.locate_ble(name).await? {
+            debug!("Found {} on BLE", name);
+            return Ok(LocatedDevice::Ble(flipper));
+        }
+
+        Err(FlipperError::NotFound)
+    }
+
+    #[cfg(feature = "ble")]
+    async fn locate_ble(&self, name: &str) -> Result<Option<BlePeripheral>, FlipperError> {
+        let scanner = FlipperScanner::new().await?;
+        let scan = scanner
+            .scan()
+            .await?
+            .filter(|adv| futures::future::ready(adv.name == name));
+        tokio::pin!(scan);
+        let found = tokio::time::timeout(self.scan_timeout, scan.next())
+            .await
+            .ok()
+            .flatten();
+        scanner.stop_scan().await?;
+
+        Ok(found.map(|adv| adv.peripheral))
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Minimal hand-rolled encoding of the few PB.Main messages the transports need themselves.
+//! Field numbers are from flipperzero-protobuf flipper.proto.
+
+use integer_encoding::VarInt;
+
+/// PB.Main command_id field.
+const MAIN_COMMAND_ID: u32 = 1;
+/// PB.Main has_next field, the last one before content oneof.
+const MAIN_HAS_NEXT: u32 = 3;
+/// PB.Main ping_request field.
+#[cfg(test)]
+const MAIN_PING_REQUEST: u32 = 5;
+/// PB.Main ping_response field.
+pub(crate) const MAIN_PING_RESPONSE: u32 = 6;
+/// PB.Main stop_session field.
+const MAIN_STOP_SESSION: u32 = 19;
+/// PB.System.PingRequest data field.
+#[cfg(test)]
+const PING_DATA: u32 = 1;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_LEN: u32 = 2;
+
+fn put_tag(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    buf.extend_from_slice(&((field << 3) | wire_type).encode_var_vec());
+}
+
+/// Encode PB.Main with given command id and embedded message content.
+fn encode_main(command_id: u32, content_field: u32, content: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    if command_id != 0 {
+        put_tag(&mut buf, MAIN_COMMAND_ID, WIRE_VARINT);
+        buf.extend_from_slice(&command_id.encode_var_vec());
+    }
+    put_tag(&mut buf, content_field, WIRE_LEN);
+    buf.extend_from_slice(&content.len().encode_var_vec());
+    buf.extend_from_slice(content);
+    buf
+}
+
+/// PB.Main frame body carrying StopSession request.
+pub(crate) fn stop_session_request(command_id: u32) -> Vec<u8> {
+    encode_main(command_id, MAIN_STOP_SESSION, &[])
+}
+
+/// PB.Main frame body carrying System.PingRequest with given payload.
+/// Only tests build pings at runtime, the serial probe has a fixed one.
+#[cfg(test)]
+pub(crate) fn ping_request(command_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut content = vec![];
+    if !data.is_empty() {
+        put_tag(&mut content, PING_DATA, WIRE_LEN);
+        content.extend_from_slice(&data.len().encode_var_vec());
+        content.extend_from_slice(data);
+    }
+    encode_main(command_id, MAIN_PING_REQUEST, &content)
+}
+
+/// Check that frame body is well-formed PB.Main and return its content field number.
+pub(crate) fn main_content_field(frame: &[u8]) -> Option<u32> {
+    parse_main(frame, false).flatten()
+}
+
+/// Check that frame body is well-formed PB.Main. Content may be absent, e.g. in error responses.
+pub(crate) fn is_main(frame: &[u8]) -> bool {
+    parse_main(frame, false).is_some()
+}
+
+/// Check that bytes received so far may begin a well-formed PB.Main.
+pub(crate) fn is_main_prefix(partial: &[u8]) -> bool {
+    parse_main(partial, true).is_some()
+}
+
+/// `None` if frame is not PB.Main, otherwise its content field number if present.
+/// With `partial`, frame may be cut anywhere.
+fn parse_main(frame: &[u8], partial: bool) -> Option<Option<u32>> {
+    let mut pos = 0;
+    let mut content = None;
+    let truncated = || if partial { Some(None) } else { None };
+
+    while pos < frame.len() {
+        let Some((tag, consumed)) = u64::decode_var(&frame[pos..]) else {
+            return truncated();
+        };
+        pos += consumed;
+        let field = (tag >> 3) as u32;
+        match (field, (tag & 0x07) as u32) {
+            (1..=MAIN_HAS_NEXT, WIRE_VARINT) => {
+                let Some((_, consumed)) = u64::decode_var(&frame[pos..]) else {
+                    return truncated();
+                };
+                pos += consumed;
+            }
+            (field, WIRE_LEN) if field > MAIN_HAS_NEXT && content.is_none() => {
+                let Some((len, consumed)) = u64::decode_var(&frame[pos..]) else {
+                    return truncated();
+                };
+                pos = pos.checked_add(consumed)?.checked_add(len as usize)?;
+                content = Some(field);
+            }
+            _ => return None,
+        }
+    }
+
+    if pos == frame.len() || partial {
+        Some(content)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_stop_session() {
+        assert_eq!(stop_session_request(0), vec![0x9a, 0x01, 0x00]);
+        assert_eq!(stop_session_request(2), vec![0x08, 0x02, 0x9a, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn encode_ping() {
+        assert_eq!(ping_request(1, &[]), vec![0x08, 0x01, 0x2a, 0x00]);
+        assert_eq!(
+            ping_request(0, &[0xaa, 0xbb]),
+            vec![0x2a, 0x04, 0x0a, 0x02, 0xaa, 0xbb]
+        );
+    }
+
+    #[test]
+    fn validate_main() {
+        // command_id: 1, command_status: OK, ping_response { data: [0xaa] }
+        let pong = [0x08, 0x01, 0x10, 0x00, 0x32, 0x03, 0x0a, 0x01, 0xaa];
+        assert_eq!(main_content_field(&pong), Some(MAIN_PING_RESPONSE));
+        assert_eq!(main_content_field(&stop_session_request(3)), Some(19));
+        // Truncated content.
+        assert_eq!(main_content_field(&pong[..8]), None);
+        // No content.
+        assert_eq!(main_content_field(&[0x08, 0x01]), None);
+        // Shell noise.
+        assert_eq!(main_content_field(b"\r\n>: "), None);
+
+        // Error response without content is still PB.Main.
+        assert!(is_main(&[0x08, 0x01, 0x10, 0x02]));
+        assert!(!is_main(&pong[..8]));
+        assert!(!is_main(b"\r\n>: "));
+
+        assert!(is_main_prefix(&pong[..5]));
+        assert!(is_main_prefix(&[]));
+        assert!(!is_main_prefix(&[0x03]));
+    }
+}
@@ -8,22 +8,116 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport};
+use super::{
+    with_timeout, FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, FrameStats,
+    TransportConfig,
+};
 use crate::codec::FlipperCodec;
 use crate::consts::{
-    BLE_OVERFLOW_CHARACTERISTIC_UUID, BLE_RX_CHARACTERISTIC_UUID, BLE_TX_CHARACTERISTIC_UUID,
+    BLE_BATTERY_LEVEL_UUID, BLE_FIRMWARE_REVISION_UUID, BLE_MANUFACTURER_NAME_UUID,
+    BLE_OVERFLOW_CHARACTERISTIC_UUID, BLE_RX_CHARACTERISTIC_UUID, BLE_SERIALSVC_UUID,
+    BLE_SERIAL_NUMBER_UUID, BLE_TX_CHARACTERISTIC_UUID,
 };
-use crate::error::FlipperError;
-use async_lock::RwLock;
+use crate::error::{FlipperError, TimeoutPhase};
+use async_stream::stream;
 use async_trait::async_trait;
-use btleplug::api::{Central, Characteristic, Manager as _, Peripheral as _, WriteType};
-use btleplug::platform::{Adapter, Manager, Peripheral};
-use bytes::BytesMut;
-use futures::stream::StreamExt;
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, Characteristic, Manager as _, Peripheral as _,
+    PeripheralProperties, ScanFilter,
+};
+use btleplug::platform::{Adapter, Manager};
+use bytes::{Bytes, BytesMut};
+use futures::stream::{Stream, StreamExt};
 use log::{debug, trace};
 use pretty_hex::*;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 use tokio_util::codec::{Decoder, Encoder};
+use uuid::Uuid;
+
+mod backend;
+#[cfg(any(test, feature = "fake-ble"))]
+pub mod fake;
+
+pub use backend::{BleBackend, BlePeripheral, Disconnections, Notifications};
+
+/// GATT write size fitting default ATT MTU of 23, used when the negotiated MTU is unknown.
+/// btleplug does not expose it, so `BlePeripheral` always falls back to this.
+pub const DEFAULT_CHUNK_SIZE: usize = 20;
+/// ATT write command header, taking up the first bytes of the MTU.
+const ATT_HEADER_LEN: usize = 3;
+/// RX notifications buffered ahead of `read_frame`.
+const RX_QUEUE_LEN: usize = 64;
+
+/// Flipper Zero body color, advertised as 16-bit service UUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlipperColor {
+    Black,
+    White,
+    Transparent,
+}
+
+impl FlipperColor {
+    fn from_services(services: &[Uuid]) -> Option<Self> {
+        services.iter().find_map(|uuid| match uuid {
+            x if *x == uuid_from_u16(0x3081) => Some(FlipperColor::Black),
+            x if *x == uuid_from_u16(0x3082) => Some(FlipperColor::White),
+            x if *x == uuid_from_u16(0x3083) => Some(FlipperColor::Transparent),
+            _ => None,
+        })
+    }
+}
+
+/// Flipper Zero seen by `FlipperScanner::scan`.
+#[derive(Clone, Debug)]
+pub struct FlipperAdvertisement {
+    /// Flipper name, e.g. "Tebirod" for "Flipper Tebirod".
+    pub name: String,
+    pub address: BDAddr,
+    pub rssi: Option<i16>,
+    pub color: Option<FlipperColor>,
+    /// Raw manufacturer specific data, keyed by company id. Firmware may put version hints here.
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+    /// Peripheral to hand over to `BTLETransport::new`.
+    pub peripheral: BlePeripheral,
+}
+
+/// Which Flipper `FlipperScanner::search` should pick.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SearchCriteria {
+    /// First Flipper whose name contains this string.
+    Name(String),
+    /// Flipper with exactly this address.
+    Address(BDAddr),
+    /// Flipper with the strongest signal seen until the timeout, taking every RSSI update
+    /// into account.
+    StrongestSignal,
+}
+
+/// Extract Flipper name, color and such from advertisement.
+/// Name comes with the scan response, so it is required to tell a Flipper apart.
+fn parse_advertisement(
+    props: PeripheralProperties,
+    peripheral: BlePeripheral,
+) -> Option<FlipperAdvertisement> {
+    let is_flipper = props.services.contains(&BLE_SERIALSVC_UUID)
+        || FlipperColor::from_services(&props.services).is_some();
+    let name = props.local_name.as_deref()?.strip_prefix("Flipper ")?;
+    if !is_flipper {
+        return None;
+    }
+
+    Some(FlipperAdvertisement {
+        name: name.to_string(),
+        address: props.address,
+        rssi: props.rssi,
+        color: FlipperColor::from_services(&props.services),
+        manufacturer_data: props.manufacturer_data,
+        peripheral,
+    })
+}
 
 pub struct FlipperScanner {
     bt_adapters: Vec<Adapter>,
@@ -77,22 +171,121 @@ impl FlipperScanner {
         }
     }
 
-    pub async fn search_flipper_by_name(&mut self, flipper_name: &str) -> Option<Peripheral> {
-        let central = &self.bt_adapters[self.adapter_idx];
+    /// Start BLE discovery on selected adapter, so nearby Flippers show up in searches.
+    pub async fn start_scan(&self) -> Result<(), FlipperError> {
+        self.bt_adapters[self.adapter_idx]
+            .start_scan(ScanFilter::default())
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
 
-        for p in central.peripherals().await.unwrap() {
-            if p.properties()
-                .await
-                .unwrap()
-                .unwrap()
-                .local_name
-                .iter()
-                .any(|name| name.contains(flipper_name))
-            {
-                return Some(p);
+    /// Stop BLE discovery on selected adapter.
+    pub async fn stop_scan(&self) -> Result<(), FlipperError> {
+        self.bt_adapters[self.adapter_idx]
+            .stop_scan()
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    /// Start discovery and stream nearby Flippers, each address reported once.
+    /// Discovery runs until the adapter is told to `stop_scan`.
+    pub async fn scan(
+        &self,
+    ) -> Result<impl Stream<Item = FlipperAdvertisement> + Send + 'static, FlipperError> {
+        let mut seen = HashSet::new();
+        Ok(self
+            .advertisements()
+            .await?
+            .filter(move |adv| futures::future::ready(seen.insert(adv.address))))
+    }
+
+    /// Start discovery and stream every Flipper advertisement, RSSI updates included.
+    async fn advertisements(
+        &self,
+    ) -> Result<impl Stream<Item = FlipperAdvertisement> + Send + 'static, FlipperError> {
+        let central = self.bt_adapters[self.adapter_idx].clone();
+        let mut events = central
+            .events()
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })?;
+        central
+            .start_scan(ScanFilter {
+                services: vec![*BLE_SERIALSVC_UUID],
+            })
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })?;
+
+        Ok(stream! {
+            while let Some(event) = events.next().await {
+                let id = match event {
+                    CentralEvent::DeviceDiscovered(id)
+                    | CentralEvent::DeviceUpdated(id)
+                    | CentralEvent::ManufacturerDataAdvertisement { id, .. }
+                    | CentralEvent::ServiceDataAdvertisement { id, .. }
+                    | CentralEvent::ServicesAdvertisement { id, .. } => id,
+                    _ => continue,
+                };
+
+                let peripheral = match central.peripheral(&id).await {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let props = match peripheral.properties().await {
+                    Ok(Some(x)) => x,
+                    _ => continue,
+                };
+                let peripheral = BlePeripheral::new(central.clone(), peripheral);
+                if let Some(adv) = parse_advertisement(props, peripheral) {
+                    trace!("Flipper {} at {}, RSSI {:?}", adv.name, adv.address, adv.rssi);
+                    yield adv;
+                }
+            }
+        })
+    }
+
+    /// Scan for a Flipper matching criteria. Fails with `FlipperError::NotFound`
+    /// if none showed up within timeout.
+    pub async fn search(
+        &self,
+        criteria: SearchCriteria,
+        timeout: Duration,
+    ) -> Result<FlipperAdvertisement, FlipperError> {
+        // Not deduplicated, so StrongestSignal sees RSSI updates of every Flipper.
+        let scan = self.advertisements().await?;
+        tokio::pin!(scan);
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut best: Option<FlipperAdvertisement> = None;
+
+        loop {
+            let adv = tokio::select! {
+                adv = scan.next() => match adv {
+                    Some(adv) => adv,
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            };
+
+            match &criteria {
+                SearchCriteria::Name(name) if adv.name.contains(name.as_str()) => {
+                    best = Some(adv);
+                    break;
+                }
+                SearchCriteria::Address(address) if adv.address == *address => {
+                    best = Some(adv);
+                    break;
+                }
+                SearchCriteria::StrongestSignal
+                    if best.as_ref().is_none_or(|x| adv.rssi > x.rssi) =>
+                {
+                    best = Some(adv);
+                }
+                _ => {}
             }
         }
-        None
+
+        self.stop_scan().await?;
+        best.ok_or(FlipperError::NotFound)
     }
 }
 
@@ -100,35 +293,75 @@ impl FlipperScanner {
 pub struct FlipperCharacteristics {
     rx: Characteristic,
     tx: Characteristic,
-    ovf: Characteristic,
 }
 
-pub struct BTLETransport {
-    flipper: Peripheral,
+/// BLE link state, see `BTLETransport::connection_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connected,
+}
+
+pub struct BTLETransport<B = BlePeripheral> {
+    flipper: B,
+    config: TransportConfig,
+    /// GATT write size override, see `with_chunk_size`.
+    chunk_size: Option<usize>,
     chars: Option<FlipperCharacteristics>,
+    /// Free space in device RX buffer, as of connection.
+    rx_buffer: u32,
+    /// Notification streams feeding the receiver and the flow control.
+    /// Opened before subscribing so that nothing is missed.
+    notifications: Option<(Notifications, Notifications)>,
+    disconnections: Option<Disconnections>,
+    state: watch::Sender<ConnectionState>,
 }
 
-impl BTLETransport {
-    pub async fn new(flipper: Peripheral) -> Self {
+impl<B: BleBackend> BTLETransport<B> {
+    pub async fn new(flipper: B) -> Self {
         Self {
             flipper,
+            config: TransportConfig::default(),
+            chunk_size: None,
             chars: None,
+            rx_buffer: 0,
+            notifications: None,
+            disconnections: None,
+            state: watch::channel(ConnectionState::Disconnected).0,
         }
     }
-}
 
-#[async_trait]
-impl FlipperTransport for BTLETransport {
-    async fn init(&mut self) -> Result<(), FlipperError> {
-        self.flipper
-            .connect()
-            .await
-            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })?;
+    /// Set connect and frame read / write timeouts.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-        self.flipper
-            .discover_services()
-            .await
-            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })?;
+    /// Set GATT write size, which frames are split into.
+    /// By default it is (MTU - 3) if the backend reports the negotiated MTU,
+    /// `DEFAULT_CHUNK_SIZE` otherwise. Override it where the platform is known
+    /// to negotiate a larger MTU without reporting it.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// GATT write size for the connected device.
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+            .or_else(|| {
+                self.flipper
+                    .mtu()
+                    .map(|mtu| mtu.saturating_sub(ATT_HEADER_LEN).max(1))
+            })
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Connect, discover Flipper characteristics and subscribe to RX and overflow.
+    async fn connect(&mut self) -> Result<(), FlipperError> {
+        self.disconnections = Some(self.flipper.disconnections().await?);
+        self.flipper.connect().await?;
+        self.flipper.discover_services().await?;
 
         let chars = self.flipper.characteristics();
         let rx = chars
@@ -147,135 +380,492 @@ impl FlipperTransport for BTLETransport {
             .ok_or(FlipperError::BTNoCharacteristics)?
             .clone();
 
-        self.flipper.subscribe(&rx).await.unwrap();
+        let rx_notifications = self.flipper.notifications().await?;
+        let ovf_notifications = self.flipper.notifications().await?;
+        self.notifications = Some((rx_notifications, ovf_notifications));
+
+        for c in [&rx, &ovf] {
+            self.flipper.subscribe(c).await?;
+        }
+        self.rx_buffer = parse_rx_buffer(&self.flipper.read(&ovf).await?)?;
+        debug!("Device RX buffer: {}", self.rx_buffer);
 
-        self.chars = Some(FlipperCharacteristics { rx, tx, ovf });
+        self.chars = Some(FlipperCharacteristics { rx, tx });
+        self.state.send_replace(ConnectionState::Connected);
 
         Ok(())
     }
 
+    /// Watch BLE link state. Once the link is lost, pending and later
+    /// `read_frame` / `write_frame` calls fail with `FlipperError::Disconnected`.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Get handle to standard Battery and Device Information services.
+    /// It stays usable after `into_channel`, alongside the RPC session.
+    pub fn services(&self) -> BleServices<B> {
+        BleServices {
+            flipper: self.flipper.clone(),
+        }
+    }
+}
+
+/// Standard GATT services of connected Flipper Zero.
+#[derive(Clone)]
+pub struct BleServices<B = BlePeripheral> {
+    flipper: B,
+}
+
+impl<B: BleBackend> BleServices<B> {
+    fn characteristic(&self, uuid: &Uuid) -> Result<Characteristic, FlipperError> {
+        self.flipper
+            .characteristics()
+            .into_iter()
+            .find(|c| c.uuid == *uuid)
+            .ok_or(FlipperError::BTNoCharacteristics)
+    }
+
+    async fn read(&self, uuid: &Uuid) -> Result<Vec<u8>, FlipperError> {
+        self.flipper.read(&self.characteristic(uuid)?).await
+    }
+
+    async fn read_string(&self, uuid: &Uuid) -> Result<String, FlipperError> {
+        let value = self.read(uuid).await?;
+        Ok(String::from_utf8_lossy(&value)
+            .trim_end_matches('\0')
+            .to_string())
+    }
+
+    /// Battery level in percent.
+    pub async fn battery_level(&self) -> Result<u8, FlipperError> {
+        self.read(&BLE_BATTERY_LEVEL_UUID)
+            .await?
+            .first()
+            .copied()
+            .ok_or_else(|| FlipperError::BTFailure("Empty battery level.".to_string()))
+    }
+
+    /// Subscribe to battery level changes, in percent.
+    pub async fn battery_notifications(
+        &self,
+    ) -> Result<impl Stream<Item = u8> + Send + 'static, FlipperError> {
+        let notifications = self.flipper.notifications().await?;
+        self.flipper
+            .subscribe(&self.characteristic(&BLE_BATTERY_LEVEL_UUID)?)
+            .await?;
+
+        Ok(notifications.filter_map(|notif| async move {
+            if notif.uuid == *BLE_BATTERY_LEVEL_UUID {
+                notif.value.first().copied()
+            } else {
+                None
+            }
+        }))
+    }
+
+    /// Firmware revision, e.g. "0.82.3".
+    pub async fn firmware_revision(&self) -> Result<String, FlipperError> {
+        self.read_string(&BLE_FIRMWARE_REVISION_UUID).await
+    }
+
+    /// Serial number.
+    pub async fn serial_number(&self) -> Result<String, FlipperError> {
+        self.read_string(&BLE_SERIAL_NUMBER_UUID).await
+    }
+
+    /// Manufacturer name.
+    pub async fn manufacturer(&self) -> Result<String, FlipperError> {
+        self.read_string(&BLE_MANUFACTURER_NAME_UUID).await
+    }
+}
+
+#[async_trait]
+impl<B: BleBackend> FlipperTransport for BTLETransport<B> {
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        with_timeout(
+            self.config.handshake_timeout,
+            TimeoutPhase::Handshake,
+            self.connect(),
+        )
+        .await
+    }
+
     fn into_channel(
         self,
     ) -> (
         Box<dyn FlipperFrameReceiver + Send + Sync>,
         Box<dyn FlipperFrameSender + Send + Sync>,
     ) {
-        let sharable_flipper = Arc::new(RwLock::new(self.flipper));
+        let chunk_size = self.chunk_size();
+        debug!("GATT write size: {}", chunk_size);
         let chars = self.chars.expect("Not initialized!");
-        (
-            Box::new(BTLEFrameReceiver::new(sharable_flipper.clone(), chars.rx)),
-            Box::new(BTLEFrameSender::new(sharable_flipper, chars.tx, chars.ovf)),
-        )
+        let (rx_notifications, ovf_notifications) = self.notifications.expect("Not initialized!");
+        // Separate pumps, so that unread RX data never stalls flow control updates.
+        let (frames_tx, frames_rx) = mpsc::channel(RX_QUEUE_LEN);
+        tokio::spawn(pump_rx(rx_notifications, frames_tx));
+        let (buffer_tx, buffer_rx) = watch::channel(self.rx_buffer);
+        tokio::spawn(watch_rx_buffer(ovf_notifications, buffer_tx));
+        let receiver =
+            BTLEFrameReceiver::new(frames_rx, chars.rx, self.state.subscribe(), self.config);
+        let sender = BTLEFrameSender::new(
+            self.flipper,
+            chars.tx,
+            buffer_rx,
+            self.state.subscribe(),
+            chunk_size,
+            self.config,
+        );
+        tokio::spawn(watch_connection(
+            self.disconnections.expect("Not initialized!"),
+            self.state,
+        ));
+
+        (Box::new(receiver), Box::new(sender))
     }
 }
 
-pub struct BTLEFrameSender {
+/// Decode overflow characteristic value: free space of device RX buffer, u32 BE.
+fn parse_rx_buffer(value: &[u8]) -> Result<u32, FlipperError> {
+    Ok(u32::from_be_bytes(value.try_into().map_err(
+        |_| -> FlipperError { FlipperError::BTFailure("Invalid overflow value.".to_string()) },
+    )?))
+}
+
+/// Mark the link lost on disconnect, until nobody watches the state anymore.
+async fn watch_connection(
+    mut disconnections: Disconnections,
+    state: watch::Sender<ConnectionState>,
+) {
+    tokio::select! {
+        // Stream end means the adapter is gone, which is no better.
+        _ = disconnections.next() => {
+            debug!("BLE link lost.");
+            state.send_replace(ConnectionState::Disconnected);
+        }
+        _ = state.closed() => {}
+    }
+}
+
+/// Resolve once the link is reported lost.
+async fn disconnected(mut state: watch::Receiver<ConnectionState>) {
+    if state
+        .wait_for(|x| *x == ConnectionState::Disconnected)
+        .await
+        .is_err()
+    {
+        // Link watcher is gone, nobody is going to report it.
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Forward RX characteristic notifications to the receiver, until either side is gone.
+async fn pump_rx(mut notifications: Notifications, frames: mpsc::Sender<Vec<u8>>) {
+    loop {
+        tokio::select! {
+            notif = notifications.next() => match notif {
+                Some(notif) if notif.uuid == *BLE_RX_CHARACTERISTIC_UUID => {
+                    if frames.send(notif.value).await.is_err() {
+                        return;
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    debug!("Notification stream ended.");
+                    return;
+                }
+            },
+            _ = frames.closed() => return,
+        }
+    }
+}
+
+/// Publish device RX buffer updates from overflow notifications, until the sender is gone.
+async fn watch_rx_buffer(mut notifications: Notifications, buffer: watch::Sender<u32>) {
+    loop {
+        tokio::select! {
+            notif = notifications.next() => match notif {
+                Some(notif) if notif.uuid == *BLE_OVERFLOW_CHARACTERISTIC_UUID => {
+                    if let Ok(free) = parse_rx_buffer(&notif.value) {
+                        trace!("Device RX buffer: {}", free);
+                        buffer.send_replace(free);
+                    }
+                }
+                Some(_) => {}
+                None => return,
+            },
+            _ = buffer.closed() => return,
+        }
+    }
+}
+
+pub struct BTLEFrameSender<B = BlePeripheral> {
     tx_characteristic: Characteristic,
-    ovf_characteristic: Characteristic,
-    flipper: Arc<RwLock<Peripheral>>,
+    flipper: B,
     codec: FlipperCodec,
+    chunk_size: usize,
+    /// Device RX buffer as last reported by the device.
+    buffer: watch::Receiver<u32>,
+    /// Device RX buffer minus what was written since the last report.
+    remaining: u32,
+    state: watch::Receiver<ConnectionState>,
+    timeout: Option<Duration>,
+    /// A timed out write may have left a partial frame on the device.
+    poisoned: bool,
 }
 
-impl BTLEFrameSender {
+impl<B: BleBackend> BTLEFrameSender<B> {
     fn new(
-        flipper: Arc<RwLock<Peripheral>>,
+        flipper: B,
         tx_chr: Characteristic,
-        ovf_chr: Characteristic,
+        buffer: watch::Receiver<u32>,
+        state: watch::Receiver<ConnectionState>,
+        chunk_size: usize,
+        config: TransportConfig,
     ) -> Self {
+        let remaining = *buffer.borrow();
+        let mut codec = FlipperCodec::default();
+        codec.set_max_frame_length(config.max_frame_length);
         Self {
             flipper,
             tx_characteristic: tx_chr,
-            ovf_characteristic: ovf_chr,
-            codec: FlipperCodec::default(),
+            codec,
+            chunk_size,
+            buffer,
+            remaining,
+            state,
+            timeout: config.write_timeout,
+            poisoned: false,
         }
     }
-}
 
-#[async_trait]
-impl FlipperFrameSender for BTLEFrameSender {
-    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+    /// Wait until device reports enough RX buffer for `len` bytes.
+    async fn reserve(&mut self, len: usize) -> Result<(), FlipperError> {
+        if self.buffer.has_changed().unwrap_or(false) {
+            self.remaining = *self.buffer.borrow_and_update();
+        }
+        while (self.remaining as usize) < len {
+            trace!("Device RX buffer full, waiting.");
+            // changed() marks the value as seen, so has_changed() would stay false here.
+            self.buffer
+                .changed()
+                .await
+                .map_err(|_| -> FlipperError { FlipperError::Disconnected })?;
+            self.remaining = *self.buffer.borrow_and_update();
+        }
+
+        self.remaining -= len as u32;
+        Ok(())
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), FlipperError> {
         let mut frame: BytesMut = BytesMut::new();
-        self.codec.encode(data, &mut frame).unwrap();
-        // TODO: Implement chunking and overflow handling
+        self.codec
+            .encode(data, &mut frame)
+            .map_err(|_| -> FlipperError { FlipperError::DataTooLarge(data.len()) })?;
         trace!("BTLE TX: {:?}\n", &frame.hex_dump());
-        let bufsz = u32::from_be_bytes(
-            self.flipper
-                .read()
-                .await
-                .read(&self.ovf_characteristic)
-                .await
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        );
-        debug!("remaining buffer: {:?}\n", bufsz);
-        self.flipper
-            .read()
-            .await
-            .write(&self.tx_characteristic, &frame, WriteType::WithoutResponse)
-            .await
-            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        for chunk in frame.chunks(self.chunk_size) {
+            self.reserve(chunk.len()).await?;
+            self.flipper.write(&self.tx_characteristic, chunk).await?;
+        }
 
         Ok(())
     }
 }
 
+#[async_trait]
+impl<B: BleBackend> FlipperFrameSender for BTLEFrameSender<B> {
+    /// Fails with `FlipperError::Disconnected` once a write timed out,
+    /// since the device may hold a partial frame.
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        if self.poisoned {
+            return Err(FlipperError::Disconnected);
+        }
+
+        let timeout = self.timeout;
+        let state = self.state.clone();
+        let res = tokio::select! {
+            biased;
+            _ = disconnected(state) => Err(FlipperError::Disconnected),
+            res = with_timeout(timeout, TimeoutPhase::Write, self.send(data)) => res,
+        };
+        self.poisoned = matches!(res, Err(FlipperError::Timeout(_)));
+        res
+    }
+}
+
 pub struct BTLEFrameReceiver {
     _rx_characteristic: Characteristic,
-    flipper: Arc<RwLock<Peripheral>>,
+    notifications: mpsc::Receiver<Vec<u8>>,
     codec: FlipperCodec,
+    /// Notification data not yet decoded into frames.
+    buf: BytesMut,
+    state: watch::Receiver<ConnectionState>,
+    timeout: Option<Duration>,
 }
 
 impl BTLEFrameReceiver {
-    fn new(flipper: Arc<RwLock<Peripheral>>, rx_chr: Characteristic) -> Self {
+    fn new(
+        notifications: mpsc::Receiver<Vec<u8>>,
+        rx_chr: Characteristic,
+        state: watch::Receiver<ConnectionState>,
+        config: TransportConfig,
+    ) -> Self {
+        let mut codec = FlipperCodec::default();
+        codec.set_resync(config.resync);
+        codec.set_max_frame_length(config.max_frame_length);
         Self {
-            flipper,
+            notifications,
             _rx_characteristic: rx_chr,
-            codec: FlipperCodec::default(),
+            codec,
+            buf: BytesMut::new(),
+            state,
+            timeout: config.read_timeout,
         }
     }
-}
-
-#[async_trait]
-impl FlipperFrameReceiver for BTLEFrameReceiver {
-    async fn read_frame(&mut self) -> Result<Vec<u8>, FlipperError> {
-        // Empty the codec first.
-        let mut buf = BytesMut::new();
-        if let Ok(Some(x)) = self.codec.decode(&mut buf) {
-            return Ok(x);
-        }
 
+    async fn receive(&mut self) -> Result<Bytes, FlipperError> {
         loop {
-            let mut notification = self
-                .flipper
-                .read()
-                .await
-                .notifications()
-                .await
-                .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?
-                .take(1);
-            let notif = notification.next().await;
-
-            if notif == None {
-                continue;
-            }
-
-            let mut buf = BytesMut::new();
-            buf.extend_from_slice(&notif.unwrap().value);
-            trace!("BTLE RX: {:?}\n", &buf.hex_dump());
-            match self.codec.decode(&mut buf) {
+            // Drain frames left over from the previous notification first.
+            match self.codec.decode(&mut self.buf) {
                 Ok(Some(x)) => return Ok(x),
                 Err(e) => return Err(FlipperError::IOFailure(e.to_string())),
-                Ok(None) => {} // Data is not ready yet, loop back and wait again.
+                Ok(None) => {}
             }
+
+            let value = self
+                .notifications
+                .recv()
+                .await
+                .ok_or(FlipperError::Disconnected)?;
+            trace!("BTLE RX: {:?}\n", &value.hex_dump());
+            self.buf.extend_from_slice(&value);
         }
     }
 }
 
+#[async_trait]
+impl FlipperFrameReceiver for BTLEFrameReceiver {
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        let timeout = self.timeout;
+        let state = self.state.clone();
+        tokio::select! {
+            biased;
+            _ = disconnected(state) => Err(FlipperError::Disconnected),
+            res = with_timeout(timeout, TimeoutPhase::Read, self.receive()) => res,
+        }
+    }
+
+    fn stats(&self) -> FrameStats {
+        self.codec.stats()
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::fake::FakePeripheral;
     use super::*;
 
+    #[test]
+    fn color_from_services() {
+        assert_eq!(
+            FlipperColor::from_services(&[*BLE_SERIALSVC_UUID, uuid_from_u16(0x3082)]),
+            Some(FlipperColor::White)
+        );
+        assert_eq!(FlipperColor::from_services(&[*BLE_SERIALSVC_UUID]), None);
+    }
+
+    async fn connect_fake(
+        fake: &FakePeripheral,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        let mut transport = BTLETransport::new(fake.clone()).await;
+        transport.init().await.unwrap();
+        transport.into_channel()
+    }
+
+    #[tokio::test]
+    async fn write_in_chunks() {
+        let fake = FakePeripheral::new(1024);
+        let (_, mut sender) = connect_fake(&fake).await;
+
+        sender.write_frame(&[0xaa; 50]).await.unwrap();
+        for len in [20, 20, 11] {
+            let (uuid, data) = fake.next_write().await.unwrap();
+            assert_eq!(uuid, *BLE_TX_CHARACTERISTIC_UUID);
+            assert_eq!(data.len(), len);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_in_mtu_sized_chunks() {
+        let fake = FakePeripheral::new(1024);
+        fake.set_mtu(32);
+        let (_, mut sender) = connect_fake(&fake).await;
+
+        sender.write_frame(&[0xaa; 50]).await.unwrap();
+        for len in [29, 22] {
+            assert_eq!(fake.next_write().await.unwrap().1.len(), len);
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_for_rx_buffer() {
+        let fake = FakePeripheral::new(30);
+        let (_, mut sender) = connect_fake(&fake).await;
+
+        let write = tokio::spawn(async move { sender.write_frame(&[0xaa; 50]).await });
+        assert_eq!(fake.next_write().await.unwrap().1.len(), 20);
+        // Only 10 bytes left on device.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), fake.next_write())
+                .await
+                .is_err()
+        );
+
+        fake.notify(*BLE_OVERFLOW_CHARACTERISTIC_UUID, &1024u32.to_be_bytes());
+        assert_eq!(fake.next_write().await.unwrap().1.len(), 20);
+        assert_eq!(fake.next_write().await.unwrap().1.len(), 11);
+        write.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reassemble_notifications() {
+        let fake = FakePeripheral::new(1024);
+        let (mut receiver, _) = connect_fake(&fake).await;
+
+        fake.notify(*BLE_RX_CHARACTERISTIC_UUID, &[0x04, 0x01, 0x02]);
+        fake.notify(*BLE_RX_CHARACTERISTIC_UUID, &[0x03, 0x04, 0x01]);
+        fake.notify(*BLE_RX_CHARACTERISTIC_UUID, &[0x05]);
+        assert_eq!(
+            receiver.read_frame().await.unwrap(),
+            vec![0x01, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(receiver.read_frame().await.unwrap(), vec![0x05]);
+    }
+
+    #[tokio::test]
+    async fn fail_pending_read_on_disconnect() {
+        let fake = FakePeripheral::new(1024);
+        let mut transport = BTLETransport::new(fake.clone()).await;
+        transport.init().await.unwrap();
+        let mut state = transport.connection_state();
+        assert_eq!(*state.borrow(), ConnectionState::Connected);
+        let (mut receiver, mut sender) = transport.into_channel();
+
+        let read = tokio::spawn(async move { receiver.read_frame().await });
+        fake.disconnect();
+        assert_eq!(read.await.unwrap(), Err(FlipperError::Disconnected));
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+        assert_eq!(
+            sender.write_frame(&[0x01]).await,
+            Err(FlipperError::Disconnected)
+        );
+    }
+
     #[tokio::test]
     async fn test() {
         let manager = Manager::new().await.unwrap();
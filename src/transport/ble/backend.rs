@@ -0,0 +1,146 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::FlipperError;
+use async_trait::async_trait;
+use btleplug::api::{
+    Central, CentralEvent, Characteristic, Peripheral as _, ValueNotification, WriteType,
+};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::stream::{Stream, StreamExt};
+use std::collections::BTreeSet;
+use std::pin::Pin;
+
+/// Stream of GATT notifications from all subscribed characteristics.
+pub type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+/// Stream which yields when the peripheral disconnects.
+pub type Disconnections = Pin<Box<dyn Stream<Item = ()> + Send>>;
+
+/// GATT operations BTLETransport needs from the peripheral.
+/// Implemented by `BlePeripheral` for btleplug, and by `FakePeripheral` for tests.
+#[async_trait]
+pub trait BleBackend: Clone + Send + Sync + 'static {
+    async fn connect(&self) -> Result<(), FlipperError>;
+
+    async fn discover_services(&self) -> Result<(), FlipperError>;
+
+    /// Characteristics found by `discover_services`.
+    fn characteristics(&self) -> BTreeSet<Characteristic>;
+
+    /// Negotiated ATT MTU, if the platform reports it.
+    fn mtu(&self) -> Option<usize>;
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, FlipperError>;
+
+    /// Write without response.
+    async fn write(&self, characteristic: &Characteristic, data: &[u8])
+        -> Result<(), FlipperError>;
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<(), FlipperError>;
+
+    /// Open a new notification stream. Every stream sees every notification.
+    async fn notifications(&self) -> Result<Notifications, FlipperError>;
+
+    /// Watch for link loss.
+    async fn disconnections(&self) -> Result<Disconnections, FlipperError>;
+}
+
+/// btleplug peripheral, along with the adapter which reports its link events.
+#[derive(Clone, Debug)]
+pub struct BlePeripheral {
+    adapter: Adapter,
+    peripheral: Peripheral,
+}
+
+impl BlePeripheral {
+    /// `peripheral` must come from `adapter`, e.g. via `Central::peripheral`.
+    pub fn new(adapter: Adapter, peripheral: Peripheral) -> Self {
+        Self {
+            adapter,
+            peripheral,
+        }
+    }
+
+    /// Get the underlying btleplug peripheral.
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+}
+
+#[async_trait]
+impl BleBackend for BlePeripheral {
+    async fn connect(&self) -> Result<(), FlipperError> {
+        btleplug::api::Peripheral::connect(&self.peripheral)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    async fn discover_services(&self) -> Result<(), FlipperError> {
+        btleplug::api::Peripheral::discover_services(&self.peripheral)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        btleplug::api::Peripheral::characteristics(&self.peripheral)
+    }
+
+    /// btleplug 0.10 does not expose the negotiated MTU.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, FlipperError> {
+        btleplug::api::Peripheral::read(&self.peripheral, characteristic)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+    ) -> Result<(), FlipperError> {
+        btleplug::api::Peripheral::write(
+            &self.peripheral,
+            characteristic,
+            data,
+            WriteType::WithoutResponse,
+        )
+        .await
+        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<(), FlipperError> {
+        btleplug::api::Peripheral::subscribe(&self.peripheral, characteristic)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    async fn notifications(&self) -> Result<Notifications, FlipperError> {
+        btleplug::api::Peripheral::notifications(&self.peripheral)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTFailure(e.to_string()) })
+    }
+
+    /// Disconnect events come from the adapter the peripheral was found on.
+    async fn disconnections(&self) -> Result<Disconnections, FlipperError> {
+        let id = self.peripheral.id();
+        let events = self
+            .adapter
+            .events()
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::BTAdapterError(e.to_string()) })?;
+        Ok(Box::pin(events.filter_map(move |event| {
+            let disconnected = matches!(event, CentralEvent::DeviceDisconnected(x) if x == id);
+            futures::future::ready(disconnected.then_some(()))
+        })))
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::backend::{BleBackend, Disconnections, Notifications};
+use crate::consts::{
+    BLE_OVERFLOW_CHARACTERISTIC_UUID, BLE_RX_CHARACTERISTIC_UUID, BLE_SERIALSVC_UUID,
+    BLE_TX_CHARACTERISTIC_UUID,
+};
+use crate::error::FlipperError;
+use async_stream::stream;
+use async_trait::async_trait;
+use btleplug::api::{CharPropFlags, Characteristic, ValueNotification};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+/// GATT write made by the host: characteristic and data.
+type Write = (Uuid, Vec<u8>);
+
+#[derive(Default)]
+struct State {
+    connected: bool,
+    characteristics: BTreeSet<Characteristic>,
+    values: HashMap<Uuid, Vec<u8>>,
+    subscribed: HashSet<Uuid>,
+    mtu: Option<usize>,
+}
+
+/// In-process Flipper Zero peripheral, for testing BLE code without radios.
+///
+/// Exposes the Flipper serial service. Tests play the device side:
+/// take what the host wrote with `next_write`, answer with `notify`.
+#[derive(Clone)]
+pub struct FakePeripheral {
+    state: Arc<Mutex<State>>,
+    notifications: broadcast::Sender<ValueNotification>,
+    disconnections: broadcast::Sender<()>,
+    writes_tx: mpsc::UnboundedSender<Write>,
+    writes_rx: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Write>>>,
+}
+
+impl FakePeripheral {
+    /// Create fake Flipper whose RX buffer has `rx_buffer` bytes free.
+    pub fn new(rx_buffer: u32) -> Self {
+        let (notifications, _) = broadcast::channel(256);
+        let (disconnections, _) = broadcast::channel(1);
+        let (writes_tx, writes_rx) = mpsc::unbounded_channel();
+        let fake = Self {
+            state: Arc::default(),
+            notifications,
+            disconnections,
+            writes_tx,
+            writes_rx: Arc::new(tokio::sync::Mutex::new(writes_rx)),
+        };
+
+        let notify = CharPropFlags::READ | CharPropFlags::NOTIFY;
+        fake.add_characteristic(
+            *BLE_SERIALSVC_UUID,
+            *BLE_RX_CHARACTERISTIC_UUID,
+            notify,
+            &[],
+        );
+        fake.add_characteristic(
+            *BLE_SERIALSVC_UUID,
+            *BLE_TX_CHARACTERISTIC_UUID,
+            CharPropFlags::WRITE_WITHOUT_RESPONSE,
+            &[],
+        );
+        fake.add_characteristic(
+            *BLE_SERIALSVC_UUID,
+            *BLE_OVERFLOW_CHARACTERISTIC_UUID,
+            notify,
+            &rx_buffer.to_be_bytes(),
+        );
+        fake
+    }
+
+    /// Add characteristic with initial value, e.g. battery level.
+    pub fn add_characteristic(
+        &self,
+        service_uuid: Uuid,
+        uuid: Uuid,
+        properties: CharPropFlags,
+        value: &[u8],
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.characteristics.insert(Characteristic {
+            uuid,
+            service_uuid,
+            properties,
+        });
+        state.values.insert(uuid, value.to_vec());
+    }
+
+    /// Set value returned by reads of characteristic.
+    pub fn set_value(&self, uuid: Uuid, value: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .values
+            .insert(uuid, value.to_vec());
+    }
+
+    /// Set value and send notification, if the host subscribed to the characteristic.
+    pub fn notify(&self, uuid: Uuid, value: &[u8]) {
+        self.set_value(uuid, value);
+        if self.state.lock().unwrap().subscribed.contains(&uuid) {
+            let _ = self.notifications.send(ValueNotification {
+                uuid,
+                value: value.to_vec(),
+            });
+        }
+    }
+
+    /// Report negotiated ATT MTU, as some platforms do.
+    pub fn set_mtu(&self, mtu: usize) {
+        self.state.lock().unwrap().mtu = Some(mtu);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    /// Drop the link, as if the device went out of range.
+    pub fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        state.subscribed.clear();
+        let _ = self.disconnections.send(());
+    }
+
+    /// Wait for the next GATT write from the host.
+    pub async fn next_write(&self) -> Option<Write> {
+        self.writes_rx.lock().await.recv().await
+    }
+
+    fn check_connected(&self) -> Result<(), FlipperError> {
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(FlipperError::BTFailure("Not connected.".to_string()))
+        }
+    }
+}
+
+#[async_trait]
+impl BleBackend for FakePeripheral {
+    async fn connect(&self) -> Result<(), FlipperError> {
+        self.state.lock().unwrap().connected = true;
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> Result<(), FlipperError> {
+        self.check_connected()
+    }
+
+    fn characteristics(&self) -> BTreeSet<Characteristic> {
+        self.state.lock().unwrap().characteristics.clone()
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.state.lock().unwrap().mtu
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>, FlipperError> {
+        self.check_connected()?;
+        self.state
+            .lock()
+            .unwrap()
+            .values
+            .get(&characteristic.uuid)
+            .cloned()
+            .ok_or(FlipperError::BTNoCharacteristics)
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+    ) -> Result<(), FlipperError> {
+        self.check_connected()?;
+        let _ = self.writes_tx.send((characteristic.uuid, data.to_vec()));
+        Ok(())
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<(), FlipperError> {
+        self.check_connected()?;
+        self.state
+            .lock()
+            .unwrap()
+            .subscribed
+            .insert(characteristic.uuid);
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<Notifications, FlipperError> {
+        let mut receiver = self.notifications.subscribe();
+        Ok(Box::pin(stream! {
+            loop {
+                match receiver.recv().await {
+                    Ok(notif) => yield notif,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }))
+    }
+
+    async fn disconnections(&self) -> Result<Disconnections, FlipperError> {
+        let mut receiver = self.disconnections.subscribe();
+        Ok(Box::pin(stream! {
+            while !matches!(receiver.recv().await, Err(RecvError::Closed)) {
+                yield ();
+            }
+        }))
+    }
+}
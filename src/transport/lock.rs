@@ -0,0 +1,188 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::FlipperError;
+use log::debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Advisory, cross-process lock of a serial device.
+///
+/// Consists of a lock file in `$XDG_RUNTIME_DIR` which records the owner pid, and
+/// `flock` on the tty itself. The latter lives as long as the port stays open,
+/// the former as long as DeviceLock is alive.
+pub struct DeviceLock {
+    _file: Option<File>,
+}
+
+impl DeviceLock {
+    /// Take the lock file for `tty`. Fails with `FlipperError::DeviceBusy` if it is taken.
+    /// Without `$XDG_RUNTIME_DIR`, only the tty lock (see `lock_tty`) protects the device.
+    pub fn acquire(tty: &str) -> Result<Self, FlipperError> {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => Self::acquire_in(Path::new(&dir), tty),
+            None => Ok(Self { _file: None }),
+        }
+    }
+
+    fn acquire_in(dir: &Path, tty: &str) -> Result<Self, FlipperError> {
+        let path = lock_path(dir, tty);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(FlipperError::DeviceBusy {
+                    pid: pid.trim().parse().ok(),
+                });
+            }
+            Err(TryLockError::Error(e)) => return Err(FlipperError::IOFailure(e.to_string())),
+        }
+
+        // Lock file is never removed, unlinking it would race with other openers.
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        debug!("Locked {} using {}", tty, path.display());
+
+        Ok(Self { _file: Some(file) })
+    }
+}
+
+/// Lock file path for tty, e.g. "/run/user/1000/flipperbridge-dev-ttyACM0.lock".
+fn lock_path(dir: &Path, tty: &str) -> PathBuf {
+    let name: String = tty
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    dir.join(format!("flipperbridge-{}.lock", name))
+}
+
+/// `flock` opened tty. Lock is released once every handle of the port is closed.
+#[cfg(unix)]
+pub fn lock_tty(port: &impl std::os::fd::AsRawFd) -> Result<(), FlipperError> {
+    use std::os::fd::BorrowedFd;
+
+    // SAFETY: fd belongs to `port`, which outlives this borrow.
+    let fd = unsafe { BorrowedFd::borrow_raw(port.as_raw_fd()) };
+    // flock belongs to the open file description, so locking a duplicate locks the port.
+    let file = File::from(
+        fd.try_clone_to_owned()
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?,
+    );
+
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(FlipperError::DeviceBusy { pid: None }),
+        Err(TryLockError::Error(e)) => Err(FlipperError::IOFailure(e.to_string())),
+    }
+}
+
+/// Byte stream which keeps DeviceLock alive along with it.
+pub struct Locked<S> {
+    inner: S,
+    _lock: Option<DeviceLock>,
+}
+
+impl<S> Locked<S> {
+    /// Wrap stream, holding `lock` until the stream is dropped.
+    pub fn new(inner: S, lock: Option<DeviceLock>) -> Self {
+        Self { inner, _lock: lock }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Locked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Locked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_path_from_tty() {
+        assert_eq!(
+            lock_path(Path::new("/run/user/1000"), "/dev/ttyACM0"),
+            PathBuf::from("/run/user/1000/flipperbridge-dev-ttyACM0.lock")
+        );
+    }
+
+    #[test]
+    fn second_lock_reports_owner() {
+        let dir = std::env::temp_dir();
+        let tty = format!("/dev/test-{}", std::process::id());
+        let _lock = DeviceLock::acquire_in(&dir, &tty).unwrap();
+        assert_eq!(
+            DeviceLock::acquire_in(&dir, &tty).err(),
+            Some(FlipperError::DeviceBusy {
+                pid: Some(std::process::id())
+            })
+        );
+        std::fs::remove_file(lock_path(&dir, &tty)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn second_tty_lock_is_busy() {
+        let (a, _b) = std::os::unix::net::UnixStream::pair().unwrap();
+        lock_tty(&a).unwrap();
+        let other = a.try_clone().unwrap();
+        // Same open file description still owns the lock.
+        lock_tty(&other).unwrap();
+
+        let path = std::env::temp_dir().join(format!("flipperbridge-test-{}", std::process::id()));
+        let first = File::create(&path).unwrap();
+        let second = File::open(&path).unwrap();
+        lock_tty(&first).unwrap();
+        assert_eq!(
+            lock_tty(&second),
+            Err(FlipperError::DeviceBusy { pid: None })
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
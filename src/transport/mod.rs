@@ -8,13 +8,84 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::error::FlipperError;
+use super::consts::MAX_FRAME_LENGTH;
+use super::error::{FlipperError, TimeoutPhase};
 use async_trait::async_trait;
+use bytes::Bytes;
+use std::future::Future;
+use std::time::Duration;
 
 #[cfg(feature = "ble")]
 pub mod ble;
+// Unix socket transport may lock devices too, serial on Windows still needs it.
+#[cfg(any(unix, feature = "serial"))]
+pub mod lock;
+pub mod pcapng;
+pub mod reconnect;
+#[cfg(feature = "replay")]
+pub mod replay;
 #[cfg(feature = "serial")]
 pub mod serial;
+pub mod stdio;
+pub mod stream;
+pub mod tap;
+#[cfg(unix)]
+pub mod unix;
+
+/// Timeouts honored by the transports. `None` means wait forever.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// Timeout for `FlipperTransport::init`.
+    pub handshake_timeout: Option<Duration>,
+    /// Timeout for a single `FlipperFrameReceiver::read_frame` call.
+    pub read_timeout: Option<Duration>,
+    /// Timeout for a single `FlipperFrameSender::write_frame` call.
+    pub write_timeout: Option<Duration>,
+    /// Skip corrupted bytes until the next valid frame instead of failing `read_frame`.
+    /// See `FlipperFrameReceiver::stats` for how much was skipped.
+    pub resync: bool,
+    /// Largest frame body sent or accepted. Firmware default is `MAX_FRAME_LENGTH`.
+    pub max_frame_length: usize,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Some(Duration::from_secs(10)),
+            // Device may stay silent for a long time between RPC events.
+            read_timeout: None,
+            write_timeout: Some(Duration::from_secs(10)),
+            resync: false,
+            max_frame_length: MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+/// Frame receiver statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Bytes dropped while looking for the next valid frame.
+    pub discarded_bytes: u64,
+    /// Number of times the stream went out of sync.
+    pub resyncs: u64,
+}
+
+/// Run future with optional timeout, mapping elapsed timeout into `FlipperError::Timeout`.
+pub(crate) async fn with_timeout<T, F>(
+    timeout: Option<Duration>,
+    phase: TimeoutPhase,
+    fut: F,
+) -> Result<T, FlipperError>
+where
+    F: Future<Output = Result<T, FlipperError>>,
+{
+    match timeout {
+        Some(duration) => tokio::time::timeout(duration, fut)
+            .await
+            .map_err(|_| -> FlipperError { FlipperError::Timeout(phase) })?,
+        None => fut.await,
+    }
+}
 
 /// Transport interface definition
 #[async_trait]
@@ -41,5 +112,26 @@ pub trait FlipperFrameSender {
 #[async_trait]
 pub trait FlipperFrameReceiver {
     /// Read FZ RPC frame. Returns frame body without frame header(length)
-    async fn read_frame(&mut self) -> Result<Vec<u8>, FlipperError>;
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError>;
+
+    /// Receiver statistics. Receivers which never resynchronize report zeros.
+    fn stats(&self) -> FrameStats {
+        FrameStats::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn timeout_reports_phase() {
+        let res: Result<(), FlipperError> = with_timeout(
+            Some(Duration::from_millis(10)),
+            TimeoutPhase::Read,
+            futures::future::pending(),
+        )
+        .await;
+        assert_eq!(res, Err(FlipperError::Timeout(TimeoutPhase::Read)));
+    }
 }
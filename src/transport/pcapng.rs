@@ -0,0 +1,182 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::tap::{Direction, FrameTap};
+use crate::error::FlipperError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// LINKTYPE_USER0. Packets are FZ RPC frame bodies, i.e. PB.Main without length prefix.
+/// Use `contrib/wireshark/flipper_rpc.lua` to dissect them.
+pub const LINKTYPE_FLIPPER_RPC: u16 = 147;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+/// Writes tapped frames into a pcapng capture, one interface per file.
+/// Timestamps have the default microsecond resolution.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+}
+
+impl PcapngWriter<BufWriter<File>> {
+    /// Create (or truncate) capture file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, FlipperError> {
+        let file = File::create(path)
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        Self::new(BufWriter::new(file))
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+    }
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Write section header and interface description into `out`.
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown.
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut out, BLOCK_SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_FLIPPER_RPC.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit.
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        put_option(&mut idb, OPT_IF_NAME, b"flipper");
+        put_option(&mut idb, OPT_END, &[]);
+        write_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, &idb)?;
+
+        out.flush()?;
+        Ok(Self { out })
+    }
+
+    /// Get back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + Send> FrameTap for PcapngWriter<W> {
+    fn record(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        frame: &[u8],
+    ) -> std::io::Result<()> {
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let flags = match direction {
+            Direction::Sent => EPB_FLAGS_OUTBOUND,
+            Direction::Received => EPB_FLAGS_INBOUND,
+        };
+
+        let mut epb = Vec::with_capacity(frame.len() + 40);
+        // Interface 0
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(frame);
+        pad(&mut epb);
+        put_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        put_option(&mut epb, OPT_END, &[]);
+        write_block(&mut self.out, BLOCK_ENHANCED_PACKET, &epb)?;
+
+        // Keep the capture usable if the process gets killed.
+        self.out.flush()
+    }
+}
+
+/// Pad block body to 32 bits.
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().div_ceil(4) * 4, 0);
+}
+
+fn put_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Write block with type, and total length both before and after the body.
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total_len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn write_capture() {
+        let mut writer = PcapngWriter::new(Vec::new()).unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+        writer
+            .record(timestamp, Direction::Sent, &[0x08, 0x01, 0x2a, 0x00])
+            .unwrap();
+        writer
+            .record(timestamp, Direction::Received, &[0x08, 0x01, 0x32])
+            .unwrap();
+        let capture = writer.into_inner();
+
+        // Section header, then interface description with if_name.
+        assert_eq!(u32_at(&capture, 0), BLOCK_SECTION_HEADER);
+        assert_eq!(u32_at(&capture, 4), 28);
+        assert_eq!(u32_at(&capture, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(&capture, 24), 28);
+        let idb = &capture[28..];
+        assert_eq!(u32_at(idb, 0), BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(u32_at(idb, 4), 36);
+        assert_eq!(u16::from_le_bytes([idb[8], idb[9]]), LINKTYPE_FLIPPER_RPC);
+
+        let sent = &idb[36..];
+        assert_eq!(u32_at(sent, 0), BLOCK_ENHANCED_PACKET);
+        assert_eq!(u32_at(sent, 4), 48);
+        assert_eq!(u32_at(sent, 12), 1);
+        assert_eq!(u32_at(sent, 16), 2);
+        assert_eq!(u32_at(sent, 20), 4);
+        assert_eq!(&sent[28..32], &[0x08, 0x01, 0x2a, 0x00]);
+        assert_eq!(u32_at(sent, 36), EPB_FLAGS_OUTBOUND);
+        assert_eq!(u32_at(sent, 44), 48);
+
+        // Packet data is padded to 32 bits.
+        let received = &sent[48..];
+        assert_eq!(u32_at(received, 4), 48);
+        assert_eq!(u32_at(received, 20), 3);
+        assert_eq!(&received[28..32], &[0x08, 0x01, 0x32, 0x00]);
+        assert_eq!(u32_at(received, 36), EPB_FLAGS_INBOUND);
+        assert_eq!(received.len(), 48);
+    }
+}
@@ -0,0 +1,459 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, FrameStats};
+use crate::error::FlipperError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{debug, warn};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+type Channel = (
+    Box<dyn FlipperFrameReceiver + Send + Sync>,
+    Box<dyn FlipperFrameSender + Send + Sync>,
+);
+type WriteRequest = (Vec<u8>, oneshot::Sender<Result<(), FlipperError>>);
+
+/// Reconnection policy of ReconnectingTransport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    /// Delay before the first reconnection attempt. Doubled on every failure.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between reconnection attempts.
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts in a row. `None` retries forever.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Transport wrapper which re-creates and re-initializes the underlying transport
+/// whenever it is lost (USB unplug, BLE disconnect, device reboot).
+///
+/// When the link drops, pending and subsequent `read_frame` / `write_frame` calls fail with
+/// `FlipperError::Disconnected` (see `FlipperError::is_retryable`) until a new RPC session is
+/// up. Requests sent before the drop are lost and must be retried by the caller.
+/// Restore frames (e.g. a screen stream start request) are re-sent on every new session.
+pub struct ReconnectingTransport<F> {
+    factory: F,
+    config: ReconnectConfig,
+    restore_frames: Vec<Vec<u8>>,
+    channel: Option<Channel>,
+}
+
+impl<F, Fut, T> ReconnectingTransport<F>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, FlipperError>> + Send + 'static,
+    T: FlipperTransport + Send + 'static,
+{
+    /// Create ReconnectingTransport from transport factory.
+    /// Factory is called for every connection attempt and should return fresh,
+    /// not yet initialized transport.
+    pub fn new(factory: F) -> Self {
+        Self {
+            factory,
+            config: ReconnectConfig::default(),
+            restore_frames: vec![],
+            channel: None,
+        }
+    }
+
+    /// Set reconnection backoff policy.
+    pub fn with_config(mut self, config: ReconnectConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Add frame to be re-sent after every reconnection, to resume streams.
+    pub fn with_restore_frame(mut self, frame: &[u8]) -> Self {
+        self.restore_frames.push(frame.to_vec());
+        self
+    }
+}
+
+/// Create and initialize transport, then re-send restore frames.
+async fn connect<F, Fut, T>(
+    factory: &mut F,
+    restore_frames: &[Vec<u8>],
+) -> Result<Channel, FlipperError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, FlipperError>>,
+    T: FlipperTransport,
+{
+    let mut transport = factory().await?;
+    transport.init().await?;
+    let (receiver, mut sender) = transport.into_channel();
+    for frame in restore_frames {
+        sender.write_frame(frame).await?;
+    }
+
+    Ok((receiver, sender))
+}
+
+/// Forward frames from receiver to the channel. Returns error which killed the link.
+/// Receiver stats are added on top of those of the previous links.
+async fn pump(
+    mut receiver: Box<dyn FlipperFrameReceiver + Send + Sync>,
+    frames: mpsc::Sender<Result<Bytes, FlipperError>>,
+    stats: Arc<Mutex<FrameStats>>,
+) -> FlipperError {
+    let base = *stats.lock().expect("Stats poisoned!");
+    loop {
+        let res = receiver.read_frame().await;
+        let link = receiver.stats();
+        *stats.lock().expect("Stats poisoned!") = FrameStats {
+            discarded_bytes: base.discarded_bytes + link.discarded_bytes,
+            resyncs: base.resyncs + link.resyncs,
+        };
+
+        let res = match res {
+            Ok(frame) => Ok(frame),
+            // Read timeout is up to the caller, link is still fine.
+            Err(e @ FlipperError::Timeout(_)) => Err(e),
+            Err(e) => return e,
+        };
+
+        // Nobody may listen anymore, but keep the link up for the sender side.
+        let _ = frames.send(res).await;
+    }
+}
+
+/// Own the link: serve write requests, watch for transport loss and reconnect.
+async fn supervise<F, Fut, T>(
+    mut factory: F,
+    config: ReconnectConfig,
+    restore_frames: Vec<Vec<u8>>,
+    mut channel: Channel,
+    frames: mpsc::Sender<Result<Bytes, FlipperError>>,
+    stats: Arc<Mutex<FrameStats>>,
+    mut requests: mpsc::Receiver<WriteRequest>,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, FlipperError>>,
+    T: FlipperTransport,
+{
+    let mut requests_open = true;
+
+    loop {
+        let (receiver, mut sender) = channel;
+        let mut reader = tokio::spawn(pump(receiver, frames.clone(), stats.clone()));
+
+        let lost = loop {
+            tokio::select! {
+                res = &mut reader => match res {
+                    Ok(e) => break e,
+                    Err(_) => return,
+                },
+                req = requests.recv(), if requests_open => match req {
+                    Some((frame, reply)) => match sender.write_frame(&frame).await {
+                        Ok(()) => {
+                            let _ = reply.send(Ok(()));
+                        }
+                        // Caller error, link is still fine.
+                        Err(e @ FlipperError::DataTooLarge(_)) => {
+                            let _ = reply.send(Err(e));
+                        }
+                        // Partial frame may be left on the link, so rebuild it.
+                        Err(FlipperError::Timeout(phase)) => {
+                            let _ = reply.send(Err(FlipperError::Timeout(phase)));
+                            break FlipperError::Timeout(phase);
+                        }
+                        Err(e) => {
+                            let _ = reply.send(Err(FlipperError::Disconnected));
+                            break e;
+                        }
+                    },
+                    None => requests_open = false,
+                },
+                _ = frames.closed(), if !requests_open => {
+                    reader.abort();
+                    return;
+                }
+            }
+        };
+
+        reader.abort();
+        warn!("Transport lost: {}. Reconnecting.", lost);
+        if frames.send(Err(FlipperError::Disconnected)).await.is_err() {
+            return;
+        }
+
+        let mut backoff = config.initial_backoff;
+        let mut attempts = 0;
+        channel = loop {
+            let sleep = tokio::time::sleep(backoff);
+            tokio::pin!(sleep);
+            // Writes issued while the link is down fail right away.
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    req = requests.recv(), if requests_open => match req {
+                        Some((_, reply)) => {
+                            let _ = reply.send(Err(FlipperError::Disconnected));
+                        }
+                        None => requests_open = false,
+                    },
+                }
+            }
+
+            match connect(&mut factory, &restore_frames).await {
+                Ok(x) => break x,
+                Err(e) => {
+                    attempts += 1;
+                    debug!("Reconnection attempt {} failed: {}", attempts, e);
+                    if config.max_attempts.is_some_and(|max| attempts >= max) {
+                        warn!("Giving up reconnection after {} attempts.", attempts);
+                        return;
+                    }
+                    backoff = std::cmp::min(backoff * 2, config.max_backoff);
+                }
+            }
+        };
+        debug!("Transport reconnected.");
+    }
+}
+
+#[async_trait]
+impl<F, Fut, T> FlipperTransport for ReconnectingTransport<F>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, FlipperError>> + Send + 'static,
+    T: FlipperTransport + Send + 'static,
+{
+    /// Create and initialize the first transport. Errors are not retried here.
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        self.channel = Some(connect(&mut self.factory, &self.restore_frames).await?);
+        Ok(())
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        let channel = self.channel.expect("Not initialized!");
+        let (frames_tx, frames_rx) = mpsc::channel(32);
+        let (requests_tx, requests_rx) = mpsc::channel(32);
+        let stats = Arc::new(Mutex::new(FrameStats::default()));
+        tokio::spawn(supervise(
+            self.factory,
+            self.config,
+            self.restore_frames,
+            channel,
+            frames_tx,
+            stats.clone(),
+            requests_rx,
+        ));
+
+        (
+            Box::new(ReconnectingFrameReceiver {
+                frames: frames_rx,
+                stats,
+            }),
+            Box::new(ReconnectingFrameSender {
+                requests: requests_tx,
+            }),
+        )
+    }
+}
+
+pub struct ReconnectingFrameSender {
+    requests: mpsc::Sender<WriteRequest>,
+}
+
+#[async_trait]
+impl FlipperFrameSender for ReconnectingFrameSender {
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.requests
+            .send((data.to_vec(), reply_tx))
+            .await
+            .map_err(|_| -> FlipperError { FlipperError::Disconnected })?;
+        reply_rx
+            .await
+            .map_err(|_| -> FlipperError { FlipperError::Disconnected })?
+    }
+}
+
+pub struct ReconnectingFrameReceiver {
+    frames: mpsc::Receiver<Result<Bytes, FlipperError>>,
+    /// Summed over every link so far.
+    stats: Arc<Mutex<FrameStats>>,
+}
+
+#[async_trait]
+impl FlipperFrameReceiver for ReconnectingFrameReceiver {
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        self.frames
+            .recv()
+            .await
+            .unwrap_or(Err(FlipperError::Disconnected))
+    }
+
+    fn stats(&self) -> FrameStats {
+        *self.stats.lock().expect("Stats poisoned!")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::TimeoutPhase;
+    use crate::transport::stream::StreamTransport;
+    use crate::transport::TransportConfig;
+    use tokio::io::AsyncWriteExt;
+    use tokio::io::DuplexStream;
+
+    #[tokio::test]
+    async fn reconnects_and_restores_session() {
+        let peers: Arc<Mutex<Vec<DuplexStream>>> = Arc::default();
+        let factory_peers = peers.clone();
+        let mut transport = ReconnectingTransport::new(move || {
+            let (a, b) = tokio::io::duplex(64);
+            factory_peers.lock().unwrap().push(b);
+            async move { Ok(StreamTransport::new(a)) }
+        })
+        .with_config(ReconnectConfig {
+            initial_backoff: Duration::from_millis(1),
+            ..ReconnectConfig::default()
+        })
+        .with_restore_frame(&[0x42]);
+        transport.init().await.unwrap();
+        let (mut receiver, mut sender) = transport.into_channel();
+
+        let first = peers.lock().unwrap().remove(0);
+        let (mut peer_rx, mut peer_tx) = StreamTransport::new(first).into_channel();
+        assert_eq!(peer_rx.read_frame().await.unwrap(), vec![0x42]);
+        sender.write_frame(&[0x01]).await.unwrap();
+        assert_eq!(peer_rx.read_frame().await.unwrap(), vec![0x01]);
+        peer_tx.write_frame(&[0x02]).await.unwrap();
+        assert_eq!(receiver.read_frame().await.unwrap(), vec![0x02]);
+
+        // Device goes away.
+        drop((peer_rx, peer_tx));
+        assert_eq!(receiver.read_frame().await, Err(FlipperError::Disconnected));
+
+        // Restore frame arrives on the new session.
+        let second = loop {
+            if let Some(peer) = peers.lock().unwrap().pop() {
+                break peer;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        let (mut peer_rx, mut peer_tx) = StreamTransport::new(second).into_channel();
+        assert_eq!(peer_rx.read_frame().await.unwrap(), vec![0x42]);
+        peer_tx.write_frame(&[0x03]).await.unwrap();
+        assert_eq!(receiver.read_frame().await.unwrap(), vec![0x03]);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_keeps_session() {
+        let connects = Arc::new(Mutex::new(0));
+        let peers: Arc<Mutex<Vec<DuplexStream>>> = Arc::default();
+        let (factory_connects, factory_peers) = (connects.clone(), peers.clone());
+        let mut transport = ReconnectingTransport::new(move || {
+            let (a, b) = tokio::io::duplex(4096);
+            *factory_connects.lock().unwrap() += 1;
+            factory_peers.lock().unwrap().push(b);
+            async move { Ok(StreamTransport::new(a)) }
+        });
+        transport.init().await.unwrap();
+        let (_, mut sender) = transport.into_channel();
+        let peer = peers.lock().unwrap().remove(0);
+        let (mut peer_rx, _peer_tx) = StreamTransport::new(peer).into_channel();
+
+        assert_eq!(
+            sender.write_frame(&[0x00; 2048]).await,
+            Err(FlipperError::DataTooLarge(2048))
+        );
+        sender.write_frame(&[0x01]).await.unwrap();
+        assert_eq!(peer_rx.read_frame().await.unwrap(), vec![0x01]);
+        assert_eq!(*connects.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn write_timeout_rebuilds_session() {
+        let connects = Arc::new(Mutex::new(0));
+        let peers: Arc<Mutex<Vec<DuplexStream>>> = Arc::default();
+        let (factory_connects, factory_peers) = (connects.clone(), peers.clone());
+        let mut transport = ReconnectingTransport::new(move || {
+            let (a, b) = tokio::io::duplex(4);
+            *factory_connects.lock().unwrap() += 1;
+            factory_peers.lock().unwrap().push(b);
+            let config = TransportConfig {
+                write_timeout: Some(Duration::from_millis(10)),
+                ..TransportConfig::default()
+            };
+            async move { Ok(StreamTransport::new(a).with_config(config)) }
+        })
+        .with_config(ReconnectConfig {
+            initial_backoff: Duration::from_millis(1),
+            ..ReconnectConfig::default()
+        });
+        transport.init().await.unwrap();
+        let (mut receiver, mut sender) = transport.into_channel();
+
+        // Peer never reads, so the frame gets stuck halfway.
+        assert_eq!(
+            sender.write_frame(&[0x08; 16]).await,
+            Err(FlipperError::Timeout(TimeoutPhase::Write))
+        );
+        assert_eq!(receiver.read_frame().await, Err(FlipperError::Disconnected));
+        while *connects.lock().unwrap() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_resync_stats() {
+        let peers: Arc<Mutex<Vec<DuplexStream>>> = Arc::default();
+        let factory_peers = peers.clone();
+        let mut transport = ReconnectingTransport::new(move || {
+            let (a, b) = tokio::io::duplex(64);
+            factory_peers.lock().unwrap().push(b);
+            let config = TransportConfig {
+                resync: true,
+                ..TransportConfig::default()
+            };
+            async move { Ok(StreamTransport::new(a).with_config(config)) }
+        });
+        transport.init().await.unwrap();
+        let (mut receiver, _sender) = transport.into_channel();
+
+        // Empty frames are garbage, then command_id: 1, ping_response { data: [0xaa] }.
+        let mut peer = peers.lock().unwrap().remove(0);
+        peer.write_all(&[0x00, 0x00, 0x00]).await.unwrap();
+        peer.write_all(&[0x07, 0x08, 0x01, 0x32, 0x03, 0x0a, 0x01, 0xaa])
+            .await
+            .unwrap();
+        receiver.read_frame().await.unwrap();
+        assert_eq!(
+            receiver.stats(),
+            FrameStats {
+                discarded_bytes: 3,
+                resyncs: 1,
+            }
+        );
+    }
+}
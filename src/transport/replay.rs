@@ -0,0 +1,444 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::tap::{Direction, FrameTap, TapTransport};
+use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport};
+use crate::error::FlipperError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+/// Transport which records its frame exchange into a JSON-lines file.
+///
+/// ```no_run
+/// # use flipper_bridge::transport::replay::{JsonLinesWriter, RecordingTransport};
+/// # use flipper_bridge::transport::serial::SerialTransport;
+/// let transport = RecordingTransport::new(
+///     SerialTransport::new("/dev/ttyACM0"),
+///     JsonLinesWriter::create("session.jsonl").unwrap(),
+/// );
+/// ```
+pub type RecordingTransport<T> = TapTransport<T, JsonLinesWriter<BufWriter<File>>>;
+
+/// One line of a recording.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Time since the recording started.
+    pub elapsed_us: u64,
+    pub direction: Direction,
+    /// Frame body, hex encoded.
+    #[serde(with = "hex")]
+    pub frame: Vec<u8>,
+}
+
+/// Writes tapped frames as `RecordedFrame` JSON lines.
+pub struct JsonLinesWriter<W: Write> {
+    out: W,
+    start: SystemTime,
+}
+
+impl JsonLinesWriter<BufWriter<File>> {
+    /// Create (or truncate) recording file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, FlipperError> {
+        let file = File::create(path)
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    /// Record into `out`, timestamps are relative to now.
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            start: SystemTime::now(),
+        }
+    }
+
+    /// Get back the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + Send> FrameTap for JsonLinesWriter<W> {
+    fn record(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        frame: &[u8],
+    ) -> std::io::Result<()> {
+        let line = RecordedFrame {
+            elapsed_us: timestamp
+                .duration_since(self.start)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            direction,
+            frame: frame.to_vec(),
+        };
+        serde_json::to_writer(&mut self.out, &line)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Fake device which plays a recording back.
+///
+/// Frames the host sends must match the recorded ones, in order. A mismatch fails
+/// that write and every later read / write with `FlipperError::IOFailure`, and
+/// `ReplayHandle::assert_finished` panics with it. Recorded device frames are delivered
+/// as soon as every host frame recorded before them was sent; timing is ignored.
+/// Once the recording is over, `read_frame` fails with `FlipperError::Disconnected`.
+pub struct ReplayTransport {
+    script: Arc<Script>,
+}
+
+impl ReplayTransport {
+    /// Play back given frames.
+    pub fn new(frames: impl IntoIterator<Item = RecordedFrame>) -> Self {
+        Self {
+            script: Arc::new(Script {
+                frames: Mutex::new(frames.into_iter().collect()),
+                mismatch: Mutex::new(None),
+                progress: Notify::new(),
+            }),
+        }
+    }
+
+    /// Play back JSON-lines recording file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, FlipperError> {
+        let file = File::open(path)
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        Self::from_reader(BufReader::new(file))
+    }
+
+    /// Play back JSON-lines recording.
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, FlipperError> {
+        let mut frames = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line =
+                line.map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let frame = serde_json::from_str(&line).map_err(|e| -> FlipperError {
+                FlipperError::IOFailure(format!("Recording line {}: {}", n + 1, e))
+            })?;
+            frames.push(frame);
+        }
+
+        Ok(Self::new(frames))
+    }
+
+    /// Handle to check the replay progress once the transport is consumed.
+    pub fn handle(&self) -> ReplayHandle {
+        ReplayHandle {
+            script: self.script.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl FlipperTransport for ReplayTransport {
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        Ok(())
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        (
+            Box::new(ReplayFrameReceiver {
+                script: self.script.clone(),
+            }),
+            Box::new(ReplayFrameSender {
+                script: self.script,
+            }),
+        )
+    }
+}
+
+/// Replay progress.
+#[derive(Clone)]
+pub struct ReplayHandle {
+    script: Arc<Script>,
+}
+
+impl ReplayHandle {
+    /// Number of recorded frames not sent or received yet.
+    pub fn remaining(&self) -> usize {
+        self.script.frames.lock().expect("Replay poisoned!").len()
+    }
+
+    /// Panic unless the whole recording was played back without a mismatch.
+    pub fn assert_finished(&self) {
+        if let Some(mismatch) = self.script.mismatch() {
+            panic!("{}", mismatch);
+        }
+        let frames = self.script.frames.lock().expect("Replay poisoned!");
+        if let Some(next) = frames.front() {
+            panic!(
+                "Replay not finished, {} frames left. Next {:?}: {}",
+                frames.len(),
+                next.direction,
+                hex::encode(&next.frame)
+            );
+        }
+    }
+}
+
+struct Script {
+    frames: Mutex<VecDeque<RecordedFrame>>,
+    /// First host frame which did not match the recording.
+    mismatch: Mutex<Option<String>>,
+    /// Notified whenever a host frame was consumed, or did not match.
+    progress: Notify,
+}
+
+impl Script {
+    fn mismatch(&self) -> Option<String> {
+        self.mismatch.lock().expect("Replay poisoned!").clone()
+    }
+
+    /// Stop the replay, waking up the receiver so it fails too.
+    fn fail(&self, mismatch: String) -> FlipperError {
+        self.mismatch
+            .lock()
+            .expect("Replay poisoned!")
+            .get_or_insert(mismatch.clone());
+        self.progress.notify_waiters();
+        FlipperError::IOFailure(mismatch)
+    }
+}
+
+struct ReplayFrameReceiver {
+    script: Arc<Script>,
+}
+
+#[async_trait]
+impl FlipperFrameReceiver for ReplayFrameReceiver {
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        loop {
+            let progress = self.script.progress.notified();
+            tokio::pin!(progress);
+            // Register before checking, so progress made meanwhile is not missed.
+            progress.as_mut().enable();
+
+            if let Some(mismatch) = self.script.mismatch() {
+                return Err(FlipperError::IOFailure(mismatch));
+            }
+            {
+                let mut frames = self.script.frames.lock().expect("Replay poisoned!");
+                match frames.front() {
+                    None => return Err(FlipperError::Disconnected),
+                    Some(x) if x.direction == Direction::Received => {
+                        let frame = frames.pop_front().unwrap().frame;
+                        return Ok(Bytes::from(frame));
+                    }
+                    // Device waits for the host request.
+                    Some(_) => {}
+                }
+            }
+
+            progress.await;
+        }
+    }
+}
+
+struct ReplayFrameSender {
+    script: Arc<Script>,
+}
+
+#[async_trait]
+impl FlipperFrameSender for ReplayFrameSender {
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        if let Some(mismatch) = self.script.mismatch() {
+            return Err(FlipperError::IOFailure(mismatch));
+        }
+
+        let mut frames = self.script.frames.lock().expect("Replay poisoned!");
+        // Device frames recorded before this one may still be unread by the host.
+        let mismatch = match frames.iter().position(|x| x.direction == Direction::Sent) {
+            None => format!("Unexpected frame after replay end: {}", hex::encode(data)),
+            Some(pos) if frames[pos].frame != data => format!(
+                "Replay mismatch at {} us.\nexpected: {}\n  actual: {}",
+                frames[pos].elapsed_us,
+                hex::encode(&frames[pos].frame),
+                hex::encode(data)
+            ),
+            Some(pos) => {
+                frames.remove(pos);
+                drop(frames);
+                self.script.progress.notify_waiters();
+                return Ok(());
+            }
+        };
+
+        drop(frames);
+        Err(self.script.fail(mismatch))
+    }
+}
+
+/// Lowercase hex (de)serialization of frame bodies.
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn encode(data: &[u8]) -> String {
+        data.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    pub(super) fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(data))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if !s.is_ascii() || s.len() % 2 != 0 {
+            return Err(D::Error::custom("invalid hex string"));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::stream::StreamTransport;
+    use std::time::Duration;
+
+    /// Ping request / response pair, as sent by `rpc::ping_request`.
+    const PING: [u8; 6] = [0x08, 0x01, 0x2a, 0x02, 0x0a, 0x00];
+    const PONG: [u8; 6] = [0x08, 0x01, 0x32, 0x02, 0x0a, 0x00];
+
+    /// Recording buffer which stays readable while the channel is alive.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn record_then_replay() {
+        // Record a session against a scripted peer.
+        let (a, b) = tokio::io::duplex(64);
+        let recording = SharedBuf::default();
+        let transport = TapTransport::new(
+            StreamTransport::new(a),
+            JsonLinesWriter::new(recording.clone()),
+        );
+        let (mut receiver, mut sender) = transport.into_channel();
+        let (mut peer_receiver, mut peer_sender) = StreamTransport::new(b).into_channel();
+
+        sender.write_frame(&PING).await.unwrap();
+        assert_eq!(peer_receiver.read_frame().await.unwrap(), PING[..]);
+        peer_sender.write_frame(&PONG).await.unwrap();
+        assert_eq!(receiver.read_frame().await.unwrap(), PONG[..]);
+
+        // Tap thread drops the writer once it has recorded everything.
+        drop((receiver, sender));
+        while Arc::strong_count(&recording.0) > 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Replay it.
+        let recording = recording.0.lock().unwrap().clone();
+        let replay = ReplayTransport::from_reader(recording.as_slice()).unwrap();
+        let handle = replay.handle();
+        assert_eq!(handle.remaining(), 2);
+        let (mut receiver, mut sender) = replay.into_channel();
+
+        // Response is held back until the request is sent.
+        let pong = tokio::spawn(async move { receiver.read_frame().await });
+        tokio::task::yield_now().await;
+        assert!(!pong.is_finished());
+        sender.write_frame(&PING).await.unwrap();
+        assert_eq!(pong.await.unwrap().unwrap(), PONG[..]);
+        handle.assert_finished();
+    }
+
+    #[test]
+    fn parse_recording() {
+        let recording = concat!(
+            r#"{"elapsed_us":0,"direction":"sent","frame":"08012a020a00"}"#,
+            "\n\n",
+            r#"{"elapsed_us":1200,"direction":"received","frame":"080132020a00"}"#,
+            "\n"
+        );
+        let replay = ReplayTransport::from_reader(recording.as_bytes()).unwrap();
+        assert_eq!(
+            replay.script.frames.lock().unwrap().back(),
+            Some(&RecordedFrame {
+                elapsed_us: 1200,
+                direction: Direction::Received,
+                frame: PONG.to_vec(),
+            })
+        );
+
+        let bad = r#"{"elapsed_us":0,"direction":"sent","frame":"0g"}"#;
+        assert!(ReplayTransport::from_reader(bad.as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn fail_on_mismatch() {
+        let replay = ReplayTransport::new([
+            RecordedFrame {
+                elapsed_us: 0,
+                direction: Direction::Sent,
+                frame: PING.to_vec(),
+            },
+            RecordedFrame {
+                elapsed_us: 1200,
+                direction: Direction::Received,
+                frame: PONG.to_vec(),
+            },
+        ]);
+        let handle = replay.handle();
+        let (mut receiver, mut sender) = replay.into_channel();
+
+        // Receiver waiting for the request gives up as soon as the mismatch happens.
+        let pong = tokio::spawn(async move { receiver.read_frame().await });
+        tokio::task::yield_now().await;
+        assert!(sender.write_frame(&PONG).await.is_err());
+        assert!(matches!(
+            pong.await.unwrap(),
+            Err(FlipperError::IOFailure(x)) if x.starts_with("Replay mismatch")
+        ));
+        assert!(sender.write_frame(&PING).await.is_err());
+
+        let report = std::panic::catch_unwind(|| handle.assert_finished()).unwrap_err();
+        assert!(report
+            .downcast_ref::<String>()
+            .unwrap()
+            .starts_with("Replay mismatch"));
+    }
+}
@@ -8,36 +8,265 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport};
-use crate::consts::PROMPT_PATTERN;
-use crate::error::FlipperError;
+use super::lock::{DeviceLock, Locked};
+use super::stream::{StreamFrameReceiver, StreamFrameSender};
+use super::{
+    with_timeout, FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, FrameStats,
+    TransportConfig,
+};
+use crate::cli::{find_subsequence, CliSession};
+use crate::consts::{PROMPT_PATTERN, USB_PID, USB_VID};
+use crate::error::{FlipperError, TimeoutPhase};
+use crate::rpc;
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use log::{debug, trace};
-use tokio::io::split;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
-use tokio_serial::{self, SerialPortBuilderExt, SerialStream};
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{self, SerialPort, SerialPortBuilderExt, SerialPortType, SerialStream};
+
+pub use tokio_serial::FlowControl;
 
 use crate::codec::FlipperCodec;
-use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// How long to wait for FZShell prompt before suspecting a leftover RPC session.
+const PROMPT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Flipper Zero found on USB.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialDevice {
+    /// Flipper name, e.g. "Tebirod".
+    pub name: String,
+    /// tty path, e.g. "/dev/ttyACM0" or "COM3".
+    pub path: String,
+}
+
+/// Serial port settings. Defaults suit Flipper Zero USB CDC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialConfig {
+    baud_rate: u32,
+    flow_control: FlowControl,
+    dtr: Option<bool>,
+    rts: Option<bool>,
+    exclusive: bool,
+    read_buffer_size: usize,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+            exclusive: true,
+            read_buffer_size: 8 * 1024,
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Create config with 115200 baud, no flow control, DTR / RTS left untouched,
+    /// exclusive mode on and 8 KiB read buffer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set baud rate. Ignored by USB CDC, matters for UART bridges.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Set flow control.
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// Drive DTR line after opening the port. Left untouched by default.
+    pub fn dtr(mut self, level: bool) -> Self {
+        self.dtr = Some(level);
+        self
+    }
+
+    /// Drive RTS line after opening the port. Left untouched by default.
+    pub fn rts(mut self, level: bool) -> Self {
+        self.rts = Some(level);
+        self
+    }
+
+    /// Set TIOCEXCL exclusive mode, which makes other `open` calls on the tty fail.
+    /// Unix only, ignored elsewhere.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Set initial capacity of frame read buffer.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Open tty with these settings.
+    fn open(&self, tty: &str) -> Result<SerialStream, FlipperError> {
+        let mut port = tokio_serial::new(tty, self.baud_rate)
+            .flow_control(self.flow_control)
+            .open_native_async()
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+
+        #[cfg(unix)]
+        port.set_exclusive(self.exclusive)
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        if let Some(level) = self.dtr {
+            port.write_data_terminal_ready(level)
+                .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        }
+        if let Some(level) = self.rts {
+            port.write_request_to_send(level)
+                .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        }
+
+        Ok(port)
+    }
+}
+
+/// Lock and open tty. Fails with `FlipperError::DeviceBusy` if another process uses it.
+pub(crate) fn open_port(
+    tty: &str,
+    config: &SerialConfig,
+) -> Result<(SerialStream, DeviceLock), FlipperError> {
+    let lock = DeviceLock::acquire(tty)?;
+    let port = config.open(tty)?;
+    #[cfg(unix)]
+    super::lock::lock_tty(&port)?;
+
+    Ok((port, lock))
+}
+
+/// Device state found by `ensure_cli`.
+#[derive(Debug, PartialEq, Eq)]
+enum Probe {
+    Prompt,
+    Rpc,
+}
+
+/// Read until either FZShell prompt or a valid ping response frame shows up.
+/// A previous session may still be streaming other frames (e.g. screen frames) ahead of it.
+///
+/// `pending` holds bytes read earlier on. Once found, it is left with the bytes past
+/// the prompt or ping response.
+async fn probe<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pending: &mut Vec<u8>,
+) -> Result<Probe, FlipperError> {
+    let mut frames = BytesMut::from(&pending[..]);
+    // Shell text may precede the frames.
+    let mut codec = FlipperCodec::default();
+    codec.set_resync(true);
+    let mut buf = [0u8; 256];
+
+    loop {
+        if let Some(pos) = find_subsequence(pending, &PROMPT_PATTERN) {
+            pending.drain(..pos + PROMPT_PATTERN.len());
+            return Ok(Probe::Prompt);
+        }
+        while let Ok(Some(frame)) = codec.decode(&mut frames) {
+            if rpc::main_content_field(&frame) == Some(rpc::MAIN_PING_RESPONSE) {
+                *pending = frames.to_vec();
+                return Ok(Probe::Rpc);
+            }
+        }
+
+        let len = stream
+            .read(&mut buf)
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        if len == 0 {
+            return Err(FlipperError::Disconnected);
+        }
+        pending.extend_from_slice(&buf[..len]);
+        frames.extend_from_slice(&buf[..len]);
+    }
+}
+
+/// PB.Main { system_ping_request { data: [0; 9] } }. In RPC mode, the probing '\r'
+/// reads as frame length, so the array length makes sure the ping fills that frame.
+const PROBE_PING: [u8; b'\r' as usize] = [0x2a, 0x0b, 0x0a, 0x09, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Bring device to FZShell, even if a previous user left it in RPC session.
+/// Returns bytes read past the final prompt.
+///
+/// The probing '\r' reads as 13 byte frame length in RPC mode, so it is followed up by
+/// a 13 byte ping request. Once the ping is answered, the session is stopped.
+async fn ensure_cli<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<Vec<u8>, FlipperError> {
+    let mut pending = vec![];
+    stream
+        .write_all(b"\r")
+        .await
+        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+    if let Ok(res) = tokio::time::timeout(PROMPT_PROBE_TIMEOUT, probe(stream, &mut pending)).await {
+        res?;
+        return Ok(pending);
+    }
+
+    debug!("No FZShell prompt. Probing for RPC session.\n");
+    stream
+        .write_all(&PROBE_PING)
+        .await
+        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+    if probe(stream, &mut pending).await? == Probe::Prompt {
+        return Ok(pending);
+    }
+
+    warn!("Device was left in RPC session. Stopping it.");
+    let mut frame = BytesMut::new();
+    FlipperCodec::default()
+        .encode(&rpc::stop_session_request(0), &mut frame)
+        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+    stream
+        .write_all(&frame)
+        .await
+        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+    while probe(stream, &mut pending).await? != Probe::Prompt {}
 
-use pretty_hex::*;
+    Ok(pending)
+}
 
-const FLIPPER_BAUD: u32 = 115200;
+/// Extract Flipper name from USB descriptors.
+/// VID:PID alone is the generic STM32 VCP one, so product ("Flipper <name>")
+/// or serial ("flip_<name>") string must match too.
+fn flipper_name(vid: u16, pid: u16, product: Option<&str>, serial: Option<&str>) -> Option<String> {
+    if vid != USB_VID || pid != USB_PID {
+        return None;
+    }
 
-/// Find subsequence in u8 slice.
-/// Code from https://stackoverflow.com/questions/35901547/how-can-i-find-a-subsequence-in-a-u8-slice
-fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+    product
+        .and_then(|x| x.strip_prefix("Flipper "))
+        .or_else(|| serial.and_then(|x| x.strip_prefix("flip_")))
+        .map(|x| x.to_string())
 }
 
-/// Serial transport for Flipper Zero
+/// Serial transport for Flipper Zero.
+///
+/// Besides splitting it with `into_channel`, initialized transport can be used
+/// directly as frame receiver / sender, and then `close`d back to FZShell.
 pub struct SerialTransport {
     tty: String,
+    config: TransportConfig,
+    serial_config: SerialConfig,
+    /// Opened port sitting at FZShell prompt.
+    port: Option<SerialStream>,
     framed: Option<Framed<SerialStream, FlipperCodec>>,
+    lock: Option<DeviceLock>,
+    /// A timed out write may have left a partial frame on the wire.
+    poisoned: bool,
 }
 
 impl SerialTransport {
@@ -46,53 +275,98 @@ impl SerialTransport {
     pub fn new(tty: &str) -> Self {
         Self {
             tty: tty.to_string(),
+            config: TransportConfig::default(),
+            serial_config: SerialConfig::default(),
+            port: None,
             framed: None,
+            lock: None,
+            poisoned: false,
         }
     }
 
-    /// Write raw bytes async-y to the stream.
-    /// Internal use only.
-    async fn write_raw(port: &mut SerialStream, data: &[u8]) -> Result<(), FlipperError> {
-        trace!("Serial Write - {}", data.hex_dump());
-        // Write to the serial port
-        let mut pos = 0;
-        while pos < data.len() {
-            let n = match port.write(&data[pos..]).await {
-                Ok(x) => x,
-                Err(e) => return Err(FlipperError::IOFailure(e.to_string())),
-            };
-            pos += n;
+    /// Enumerate serial ports and find Flipper Zeros by USB descriptors.
+    pub fn discover() -> Result<Vec<SerialDevice>, FlipperError> {
+        let ports = tokio_serial::available_ports()
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+
+        Ok(ports
+            .into_iter()
+            .filter_map(|port| match port.port_type {
+                SerialPortType::UsbPort(info) => flipper_name(
+                    info.vid,
+                    info.pid,
+                    info.product.as_deref(),
+                    info.serial_number.as_deref(),
+                )
+                .map(|name| SerialDevice {
+                    name,
+                    path: port.port_name,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Create SerialTransport from already opened port at FZShell prompt,
+    /// e.g. one returned by `close`. `init` re-enters RPC mode on it.
+    /// Pass the lock returned along with the port to `with_lock`.
+    pub fn from_port(port: SerialStream) -> Self {
+        Self {
+            tty: port.name().unwrap_or_default(),
+            config: TransportConfig::default(),
+            serial_config: SerialConfig::default(),
+            port: Some(port),
+            framed: None,
+            lock: None,
+            poisoned: false,
         }
+    }
 
-        port.flush().await.unwrap();
+    /// Set handshake and frame read / write timeouts.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
 
-        Ok(())
+    /// Keep device lock, e.g. one returned by `close`, for as long as the transport lives.
+    pub fn with_lock(mut self, lock: DeviceLock) -> Self {
+        self.lock = Some(lock);
+        self
     }
 
-    /// Drain Serial stream until specific pattern.
-    /// Like, draining until FZShell prompt. Internal use only.
-    async fn drain_until_pattern(
-        port: &mut SerialStream,
-        pattern: &[u8],
-    ) -> Result<(), FlipperError> {
-        let mut patternbuf: Vec<u8> = vec![];
-        let mut buf = [0u8; 1024];
+    /// Set serial port settings. Only used when the transport opens the port itself.
+    pub fn with_serial_config(mut self, serial_config: SerialConfig) -> Self {
+        self.serial_config = serial_config;
+        self
+    }
 
-        // TODO: Implement timeout.
-        loop {
-            let readsz = port.read(&mut buf).await.unwrap();
-            trace!("Serial Read - {}", buf[0..readsz].hex_dump());
-            patternbuf.extend_from_slice(&buf[0..readsz]);
+    /// Codec for the RPC session, set up from `config`.
+    /// `close` sends stop_session through it too, so it applies until the session ends.
+    fn session_codec(&self) -> FlipperCodec {
+        let mut codec = FlipperCodec::default();
+        codec.set_resync(self.config.resync);
+        codec.set_max_frame_length(self.config.max_frame_length);
+        codec
+    }
 
-            if patternbuf.len() > 32 {
-                patternbuf.drain(0..(patternbuf.len() - 32));
-            }
+    /// Stop RPC session and hand back the raw port, sitting at FZShell prompt, along with
+    /// the lock file taken by `init` (`None` if the transport did not open the port).
+    /// The tty `flock` lasts until the port is dropped, the lock file until the lock is.
+    pub async fn close(mut self) -> Result<(SerialStream, Option<DeviceLock>), FlipperError> {
+        let mut framed = self
+            .framed
+            .take()
+            .ok_or_else(|| FlipperError::IOFailure("RPC session is not active.".to_string()))?;
 
-            match find_subsequence(&patternbuf, pattern) {
-                Some(_) => return Ok(()),
-                None => {}
-            }
-        }
+        framed
+            .send(&rpc::stop_session_request(0)[..])
+            .await
+            .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })?;
+        let mut cli = CliSession::new(framed.into_inner()).with_config(self.config);
+        cli.wait_prompt().await?;
+        debug!("RPC session closed. Back to FZShell.\n");
+
+        Ok((cli.into_inner(), self.lock.take()))
     }
 }
 
@@ -101,16 +375,37 @@ impl FlipperTransport for SerialTransport {
     /// Initialize and prepare serial stream for FZ RPC communication.
     /// Must be called before start sending / receiving RPC command frames.
     async fn init(&mut self) -> Result<(), FlipperError> {
-        let mut port = tokio_serial::new(&self.tty, FLIPPER_BAUD)
-            .open_native_async()
-            .unwrap();
-        Self::drain_until_pattern(&mut port, &PROMPT_PATTERN).await?;
-        debug!("FZShell detected. Running start_rpc_session\n");
+        let mut port = match self.port.take() {
+            Some(port) => port,
+            None => {
+                let (port, lock) = open_port(&self.tty, &self.serial_config)?;
+                self.lock = Some(lock);
+                port
+            }
+        };
+
+        let leftover = with_timeout(
+            self.config.handshake_timeout,
+            TimeoutPhase::Handshake,
+            ensure_cli(&mut port),
+        )
+        .await?;
 
-        Self::write_raw(&mut port, "start_rpc_session\r".as_bytes()).await?;
-        Self::drain_until_pattern(&mut port, "start_rpc_session\r\n".as_bytes()).await?;
+        let mut cli = CliSession::new(port)
+            .with_config(self.config)
+            .with_buffered(&leftover);
+        cli.init().await?;
+        debug!("FZShell detected. Running start_rpc_session\n");
+        let (port, leftover) = cli.start_rpc_session().await?;
         debug!("Got command response.\n");
-        self.framed = Some(Framed::new(port, FlipperCodec::default()));
+        let mut framed = Framed::with_capacity(
+            port,
+            self.session_codec(),
+            self.serial_config.read_buffer_size,
+        );
+        framed.read_buffer_mut().extend_from_slice(&leftover);
+        self.framed = Some(framed);
+        self.poisoned = false;
 
         Ok(())
     }
@@ -121,59 +416,167 @@ impl FlipperTransport for SerialTransport {
         Box<dyn FlipperFrameReceiver + Send + Sync>,
         Box<dyn FlipperFrameSender + Send + Sync>,
     ) {
-        let (rx, tx) = split(self.framed.unwrap().into_inner());
+        let parts = self.framed.unwrap().into_parts();
+        let (rx, tx) = tokio::io::split(Locked::new(parts.io, self.lock));
 
         (
-            Box::new(SerialFrameReceiver::new(rx)),
-            Box::new(SerialFrameSender::new(tx)),
+            Box::new(
+                StreamFrameReceiver::with_capacity(rx, self.serial_config.read_buffer_size)
+                    .with_buffered(&parts.read_buf)
+                    .with_timeout(self.config.read_timeout)
+                    .with_resync(self.config.resync)
+                    .with_max_frame_length(self.config.max_frame_length),
+            ),
+            Box::new(
+                StreamFrameSender::new(tx)
+                    .with_timeout(self.config.write_timeout)
+                    .with_max_frame_length(self.config.max_frame_length),
+            ),
         )
     }
 }
 
-struct SerialFrameSender {
-    framed: FramedWrite<WriteHalf<SerialStream>, FlipperCodec>,
-}
-
-impl SerialFrameSender {
-    fn new(write_stream: WriteHalf<SerialStream>) -> Self {
-        Self {
-            framed: FramedWrite::new(write_stream, FlipperCodec::default()),
-        }
-    }
-}
-
 #[async_trait]
-impl FlipperFrameSender for SerialFrameSender {
+impl FlipperFrameSender for SerialTransport {
     /// Write(send) FZ RPC frame. Frame header will be automatically calculated and appended.
+    /// Once a write timed out, later writes fail with `FlipperError::Disconnected`.
     async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
-        match self.framed.send(data).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(FlipperError::IOFailure(e.to_string())),
+        let framed = self.framed.as_mut().expect("Not initialized!");
+        if self.poisoned {
+            return Err(FlipperError::Disconnected);
         }
+        // Caller error, not a broken stream.
+        if data.len() > framed.codec().max_frame_length() {
+            return Err(FlipperError::DataTooLarge(data.len()));
+        }
+
+        let res = with_timeout(self.config.write_timeout, TimeoutPhase::Write, async {
+            framed
+                .send(data)
+                .await
+                .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+        })
+        .await;
+        self.poisoned = matches!(res, Err(FlipperError::Timeout(_)));
+        res
     }
 }
 
-struct SerialFrameReceiver {
-    framed: FramedRead<ReadHalf<SerialStream>, FlipperCodec>,
-}
+#[async_trait]
+impl FlipperFrameReceiver for SerialTransport {
+    /// Read variable size FZ RPC frame.
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        let framed = self.framed.as_mut().expect("Not initialized!");
+        with_timeout(self.config.read_timeout, TimeoutPhase::Read, async {
+            match framed.next().await {
+                Some(x) => {
+                    x.map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+                }
+                None => Err(FlipperError::Disconnected),
+            }
+        })
+        .await
+    }
 
-impl SerialFrameReceiver {
-    fn new(read_stream: ReadHalf<SerialStream>) -> Self {
-        Self {
-            framed: FramedRead::new(read_stream, FlipperCodec::default()),
-        }
+    fn stats(&self) -> FrameStats {
+        self.framed
+            .as_ref()
+            .map(|framed| framed.codec().stats())
+            .unwrap_or_default()
     }
 }
 
-#[async_trait]
-impl FlipperFrameReceiver for SerialFrameReceiver {
-    /// Read variable size FZ RPC frame.
-    async fn read_frame(&mut self) -> Result<Vec<u8>, FlipperError> {
-        loop {
-            match self.framed.next().await {
-                None => {}
-                Some(x) => return Ok(x.unwrap()),
-            };
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_from_cli() {
+        let (mut host, mut device) = tokio::io::duplex(256);
+        let fake = tokio::spawn(async move {
+            let mut buf = [0u8; 1];
+            device.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\r");
+            // Output of a command typed right after is read along with the prompt.
+            device.write_all(b"\r\n>: info\r\n").await.unwrap();
+        });
+
+        assert_eq!(ensure_cli(&mut host).await.unwrap(), b"info\r\n");
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_from_rpc_session() {
+        let (mut host, device) = tokio::io::duplex(256);
+        let fake = tokio::spawn(async move {
+            let mut framed = Framed::new(device, FlipperCodec::default());
+            let ping = framed.next().await.unwrap().unwrap();
+            assert_eq!(ping, rpc::ping_request(0, &[0u8; 9]));
+            assert_eq!(ping, PROBE_PING[..]);
+            // ping_response { data: [0; 9] }
+            let mut pong = vec![0x32, 0x0b, 0x0a, 0x09];
+            pong.extend_from_slice(&[0u8; 9]);
+            framed.send(&pong[..]).await.unwrap();
+            let stop = framed.next().await.unwrap().unwrap();
+            assert_eq!(stop, rpc::stop_session_request(0));
+
+            let mut device = framed.into_inner();
+            device.write_all(b"\r\n>: ").await.unwrap();
+        });
+
+        ensure_cli(&mut host).await.unwrap();
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_from_streaming_rpc_session() {
+        let (mut host, device) = tokio::io::duplex(256);
+        let fake = tokio::spawn(async move {
+            let mut framed = Framed::new(device, FlipperCodec::default());
+            framed.next().await.unwrap().unwrap();
+            // gui_screen_frame { data: [1, 2, 3, 4] } still streaming, then the pong.
+            let screen = [0xb2, 0x01, 0x06, 0x0a, 0x04, 0x01, 0x02, 0x03, 0x04];
+            let mut pong = vec![0x32, 0x0b, 0x0a, 0x09];
+            pong.extend_from_slice(&[0u8; 9]);
+            let mut burst = BytesMut::new();
+            let mut codec = FlipperCodec::default();
+            codec.encode(&screen[..], &mut burst).unwrap();
+            codec.encode(&screen[..], &mut burst).unwrap();
+            codec.encode(&pong[..], &mut burst).unwrap();
+            framed.get_mut().write_all(&burst).await.unwrap();
+
+            let stop = framed.next().await.unwrap().unwrap();
+            assert_eq!(stop, rpc::stop_session_request(0));
+            framed.get_mut().write_all(b"\r\n>: ").await.unwrap();
+        });
+
+        ensure_cli(&mut host).await.unwrap();
+        fake.await.unwrap();
+    }
+
+    #[test]
+    fn match_flipper_usb_descriptors() {
+        assert_eq!(
+            flipper_name(
+                0x0483,
+                0x5740,
+                Some("Flipper Tebirod"),
+                Some("flip_Tebirod")
+            ),
+            Some("Tebirod".to_string())
+        );
+        assert_eq!(
+            flipper_name(0x0483, 0x5740, None, Some("flip_Tebirod")),
+            Some("Tebirod".to_string())
+        );
+        // Some other STM32 VCP device.
+        assert_eq!(
+            flipper_name(0x0483, 0x5740, Some("STM32 Virtual ComPort"), None),
+            None
+        );
+        assert_eq!(
+            flipper_name(0x1234, 0x5740, Some("Flipper Tebirod"), None),
+            None
+        );
     }
 }
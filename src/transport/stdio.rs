@@ -0,0 +1,58 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::stream::StreamTransport;
+use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, TransportConfig};
+use crate::error::FlipperError;
+use async_trait::async_trait;
+use tokio::io::{join, stdin, stdout};
+
+/// Standard input / output transport.
+/// Frames are read from stdin and written to stdout, which makes it possible
+/// to run as a subprocess of something that relays raw FZ RPC frames
+/// (e.g. `ssh host flipperbridge-cli pipe`).
+///
+/// Nothing else may write to stdout while this transport is in use.
+#[derive(Default)]
+pub struct StdioTransport {
+    config: TransportConfig,
+}
+
+impl StdioTransport {
+    /// Create StdioTransport.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set frame read / write timeouts.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+#[async_trait]
+impl FlipperTransport for StdioTransport {
+    /// Stdio does not need any preparation.
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        Ok(())
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        StreamTransport::new(join(stdin(), stdout()))
+            .with_config(self.config)
+            .into_channel()
+    }
+}
@@ -0,0 +1,287 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::{
+    with_timeout, FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, FrameStats,
+    TransportConfig,
+};
+use crate::codec::FlipperCodec;
+use crate::error::{FlipperError, TimeoutPhase};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use std::time::Duration;
+use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Transport on top of any async byte stream (PTY, TLS stream, pipe, ...)
+/// which already speaks FZ RPC frames.
+/// Use `tokio::io::join` to combine separate read / write halves.
+pub struct StreamTransport<S> {
+    stream: S,
+    config: TransportConfig,
+}
+
+impl<S> StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    /// Create StreamTransport from byte stream.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            config: TransportConfig::default(),
+        }
+    }
+
+    /// Set timeouts used by the frame receiver / sender.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Get back the underlying byte stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[async_trait]
+impl<S> FlipperTransport for StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    /// Stream is expected to be ready for FZ RPC communication already.
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        Ok(())
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        let (rx, tx) = split(self.stream);
+
+        (
+            Box::new(
+                StreamFrameReceiver::new(rx)
+                    .with_timeout(self.config.read_timeout)
+                    .with_resync(self.config.resync)
+                    .with_max_frame_length(self.config.max_frame_length),
+            ),
+            Box::new(
+                StreamFrameSender::new(tx)
+                    .with_timeout(self.config.write_timeout)
+                    .with_max_frame_length(self.config.max_frame_length),
+            ),
+        )
+    }
+}
+
+/// Frame sender on top of any async byte sink.
+pub struct StreamFrameSender<W> {
+    framed: FramedWrite<W, FlipperCodec>,
+    timeout: Option<Duration>,
+    /// A timed out write may have left a partial frame on the wire.
+    poisoned: bool,
+}
+
+impl<W: AsyncWrite + Unpin> StreamFrameSender<W> {
+    /// Create StreamFrameSender from write half of the stream.
+    pub fn new(write_stream: W) -> Self {
+        Self {
+            framed: FramedWrite::new(write_stream, FlipperCodec::default()),
+            timeout: None,
+            poisoned: false,
+        }
+    }
+
+    /// Set per-frame write timeout. Once a write timed out, later writes fail with
+    /// `FlipperError::Disconnected`, since the device may hold a partial frame.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set largest frame body which may be sent.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.framed
+            .encoder_mut()
+            .set_max_frame_length(max_frame_length);
+        self
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send + Sync> FlipperFrameSender for StreamFrameSender<W> {
+    /// Write(send) FZ RPC frame. Frame header will be automatically calculated and appended.
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        if self.poisoned {
+            return Err(FlipperError::Disconnected);
+        }
+        // Caller error, not a broken stream.
+        if data.len() > self.framed.encoder().max_frame_length() {
+            return Err(FlipperError::DataTooLarge(data.len()));
+        }
+
+        let res = with_timeout(self.timeout, TimeoutPhase::Write, async {
+            match self.framed.send(data).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(FlipperError::IOFailure(e.to_string())),
+            }
+        })
+        .await;
+        self.poisoned = matches!(res, Err(FlipperError::Timeout(_)));
+        res
+    }
+}
+
+/// Frame receiver on top of any async byte source.
+pub struct StreamFrameReceiver<R> {
+    framed: FramedRead<R, FlipperCodec>,
+    timeout: Option<Duration>,
+}
+
+impl<R: AsyncRead + Unpin> StreamFrameReceiver<R> {
+    /// Create StreamFrameReceiver from read half of the stream.
+    pub fn new(read_stream: R) -> Self {
+        Self {
+            framed: FramedRead::new(read_stream, FlipperCodec::default()),
+            timeout: None,
+        }
+    }
+
+    /// Create StreamFrameReceiver with given initial read buffer capacity.
+    pub fn with_capacity(read_stream: R, capacity: usize) -> Self {
+        Self {
+            framed: FramedRead::with_capacity(read_stream, FlipperCodec::default(), capacity),
+            timeout: None,
+        }
+    }
+
+    /// Set per-frame read timeout.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Decode `data`, already read off the stream, before reading further.
+    pub fn with_buffered(mut self, data: &[u8]) -> Self {
+        self.framed.read_buffer_mut().extend_from_slice(data);
+        self
+    }
+
+    /// Skip corrupted bytes until the next valid frame instead of failing.
+    pub fn with_resync(mut self, resync: bool) -> Self {
+        self.framed.decoder_mut().set_resync(resync);
+        self
+    }
+
+    /// Set largest frame body which is accepted.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.framed
+            .decoder_mut()
+            .set_max_frame_length(max_frame_length);
+        self
+    }
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send + Sync> FlipperFrameReceiver for StreamFrameReceiver<R> {
+    /// Read variable size FZ RPC frame.
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        with_timeout(self.timeout, TimeoutPhase::Read, async {
+            match self.framed.next().await {
+                Some(x) => {
+                    x.map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+                }
+                None => Err(FlipperError::Disconnected),
+            }
+        })
+        .await
+    }
+
+    fn stats(&self) -> FrameStats {
+        self.framed.decoder().stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_roundtrip_over_duplex() {
+        let (a, b) = tokio::io::duplex(64);
+        let (mut receiver, mut sender) = StreamTransport::new(a).into_channel();
+        let (mut peer_receiver, mut peer_sender) = StreamTransport::new(b).into_channel();
+
+        sender.write_frame(&[0x01, 0x02, 0x03]).await.unwrap();
+        assert_eq!(
+            peer_receiver.read_frame().await.unwrap(),
+            vec![0x01, 0x02, 0x03]
+        );
+
+        peer_sender.write_frame(&[0x04]).await.unwrap();
+        assert_eq!(receiver.read_frame().await.unwrap(), vec![0x04]);
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let (a, _b) = tokio::io::duplex(64);
+        let config = TransportConfig {
+            read_timeout: Some(Duration::from_millis(10)),
+            ..TransportConfig::default()
+        };
+        let (mut receiver, _) = StreamTransport::new(a).with_config(config).into_channel();
+        assert_eq!(
+            receiver.read_frame().await,
+            Err(FlipperError::Timeout(TimeoutPhase::Read))
+        );
+    }
+
+    #[tokio::test]
+    async fn write_timeout_poisons_sender() {
+        // Peer never reads, so the frame gets stuck halfway.
+        let (a, _b) = tokio::io::duplex(4);
+        let config = TransportConfig {
+            write_timeout: Some(Duration::from_millis(10)),
+            ..TransportConfig::default()
+        };
+        let (_, mut sender) = StreamTransport::new(a).with_config(config).into_channel();
+        assert_eq!(
+            sender.write_frame(&[0x08; 16]).await,
+            Err(FlipperError::Timeout(TimeoutPhase::Write))
+        );
+        assert_eq!(
+            sender.write_frame(&[0x01]).await,
+            Err(FlipperError::Disconnected)
+        );
+    }
+
+    #[tokio::test]
+    async fn max_frame_length_per_session() {
+        let (a, b) = tokio::io::duplex(4096);
+        let config = TransportConfig {
+            max_frame_length: 2048,
+            ..TransportConfig::default()
+        };
+        let (_, mut sender) = StreamTransport::new(a).with_config(config).into_channel();
+        let (mut receiver, _) = StreamTransport::new(b).into_channel();
+
+        sender.write_frame(&[0x08; 2048]).await.unwrap();
+        assert!(sender.write_frame(&[0x08; 2049]).await.is_err());
+        // Peer keeps the firmware default.
+        assert!(receiver.read_frame().await.is_err());
+    }
+}
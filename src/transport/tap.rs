@@ -0,0 +1,217 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::{FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, FrameStats};
+use crate::error::FlipperError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use std::sync::mpsc;
+use std::time::SystemTime;
+
+/// Frame direction, as seen from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "replay",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Direction {
+    /// Host to device.
+    Sent,
+    /// Device to host.
+    Received,
+}
+
+/// Observer of every frame passing through a `TapTransport`.
+/// Runs on a thread of its own, so blocking I/O is fine here.
+pub trait FrameTap: Send {
+    /// Record frame body. Errors are logged, but never fail the session.
+    fn record(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        frame: &[u8],
+    ) -> std::io::Result<()>;
+}
+
+/// Transport wrapper which feeds every sent and received frame into a `FrameTap`.
+pub struct TapTransport<T, P> {
+    inner: T,
+    tap: P,
+}
+
+impl<T, P> TapTransport<T, P>
+where
+    T: FlipperTransport + Send,
+    P: FrameTap + 'static,
+{
+    /// Wrap transport, tapping its frames into `tap`.
+    pub fn new(inner: T, tap: P) -> Self {
+        Self { inner, tap }
+    }
+}
+
+#[async_trait]
+impl<T, P> FlipperTransport for TapTransport<T, P>
+where
+    T: FlipperTransport + Send,
+    P: FrameTap + 'static,
+{
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        self.inner.init().await
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        tap_channel(self.inner.into_channel(), self.tap)
+    }
+}
+
+/// Tap receiver / sender pair which was already created, e.g. by `LocatedDevice::connect`.
+pub fn tap_channel<P: FrameTap + 'static>(
+    (receiver, sender): (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ),
+    tap: P,
+) -> (
+    Box<dyn FlipperFrameReceiver + Send + Sync>,
+    Box<dyn FlipperFrameSender + Send + Sync>,
+) {
+    let tap = spawn_tap(tap);
+
+    (
+        Box::new(TapFrameReceiver {
+            inner: receiver,
+            tap: tap.clone(),
+        }),
+        Box::new(TapFrameSender { inner: sender, tap }),
+    )
+}
+
+/// Frame on its way to the tap thread.
+type TapEntry = (SystemTime, Direction, Bytes);
+
+/// Move `tap` onto a writer thread, keeping file I/O off the executor.
+/// Frames are recorded in order; the thread exits once every sender is dropped.
+fn spawn_tap<P: FrameTap + 'static>(mut tap: P) -> mpsc::Sender<TapEntry> {
+    let (tx, rx) = mpsc::channel::<TapEntry>();
+    std::thread::Builder::new()
+        .name("frame-tap".to_string())
+        .spawn(move || {
+            for (timestamp, direction, frame) in rx {
+                if let Err(e) = tap.record(timestamp, direction, &frame) {
+                    warn!("Failed to record {:?} frame: {}", direction, e);
+                }
+            }
+        })
+        .expect("Failed to spawn tap thread!");
+    tx
+}
+
+fn record(tap: &mpsc::Sender<TapEntry>, direction: Direction, frame: Bytes) {
+    if tap.send((SystemTime::now(), direction, frame)).is_err() {
+        warn!("Tap thread is gone, {:?} frame not recorded", direction);
+    }
+}
+
+struct TapFrameReceiver {
+    inner: Box<dyn FlipperFrameReceiver + Send + Sync>,
+    tap: mpsc::Sender<TapEntry>,
+}
+
+#[async_trait]
+impl FlipperFrameReceiver for TapFrameReceiver {
+    async fn read_frame(&mut self) -> Result<Bytes, FlipperError> {
+        let frame = self.inner.read_frame().await?;
+        record(&self.tap, Direction::Received, frame.clone());
+        Ok(frame)
+    }
+
+    fn stats(&self) -> FrameStats {
+        self.inner.stats()
+    }
+}
+
+struct TapFrameSender {
+    inner: Box<dyn FlipperFrameSender + Send + Sync>,
+    tap: mpsc::Sender<TapEntry>,
+}
+
+#[async_trait]
+impl FlipperFrameSender for TapFrameSender {
+    async fn write_frame(&mut self, data: &[u8]) -> Result<(), FlipperError> {
+        // Recorded even if the write fails, the capture shows what was attempted.
+        record(&self.tap, Direction::Sent, Bytes::copy_from_slice(data));
+        self.inner.write_frame(data).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::stream::StreamTransport;
+    use std::time::Duration;
+
+    type Entry = (Direction, Vec<u8>);
+
+    /// Hands recorded frames over to the test, from the tap thread.
+    struct Log(mpsc::Sender<Entry>);
+
+    impl FrameTap for Log {
+        fn record(
+            &mut self,
+            _timestamp: SystemTime,
+            direction: Direction,
+            frame: &[u8],
+        ) -> std::io::Result<()> {
+            self.0.send((direction, frame.to_vec())).unwrap();
+            Ok(())
+        }
+    }
+
+    fn next_entry(log: &mpsc::Receiver<Entry>) -> Entry {
+        log.recv_timeout(Duration::from_secs(1)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn record_both_directions() {
+        let (a, b) = tokio::io::duplex(64);
+        let (log_tx, log) = mpsc::channel();
+        let (mut receiver, mut sender) =
+            TapTransport::new(StreamTransport::new(a), Log(log_tx)).into_channel();
+        let (mut peer_receiver, mut peer_sender) = StreamTransport::new(b).into_channel();
+
+        sender.write_frame(&[0x01]).await.unwrap();
+        peer_receiver.read_frame().await.unwrap();
+        peer_sender.write_frame(&[0x02, 0x03]).await.unwrap();
+        receiver.read_frame().await.unwrap();
+
+        assert_eq!(next_entry(&log), (Direction::Sent, vec![0x01]));
+        assert_eq!(next_entry(&log), (Direction::Received, vec![0x02, 0x03]));
+    }
+
+    #[tokio::test]
+    async fn record_failed_write() {
+        let (a, b) = tokio::io::duplex(64);
+        drop(b);
+        let (log_tx, log) = mpsc::channel();
+        let (_, mut sender) =
+            TapTransport::new(StreamTransport::new(a), Log(log_tx)).into_channel();
+
+        assert!(sender.write_frame(&[0x01]).await.is_err());
+        assert_eq!(next_entry(&log), (Direction::Sent, vec![0x01]));
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * SPDX-FileCopyrightText: 2022 perillamint
+ *
+ * SPDX-License-Identifier: MPL-2.0
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::stream::StreamTransport;
+use super::{
+    with_timeout, FlipperFrameReceiver, FlipperFrameSender, FlipperTransport, TransportConfig,
+};
+use crate::error::{FlipperError, TimeoutPhase};
+use async_trait::async_trait;
+use log::debug;
+use std::path::{Path, PathBuf};
+use tokio::net::UnixStream;
+
+/// Unix domain socket transport for Flipper Zero.
+/// The peer is expected to relay raw FZ RPC frames, e.g.
+/// `socat UNIX-LISTEN:/tmp/flipper.sock EXEC:'flipperbridge-cli pipe'`
+pub struct UnixSocketTransport {
+    path: PathBuf,
+    config: TransportConfig,
+    stream: Option<UnixStream>,
+}
+
+impl UnixSocketTransport {
+    /// Create UnixSocketTransport using socket path.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            config: TransportConfig::default(),
+            stream: None,
+        }
+    }
+
+    /// Create UnixSocketTransport from already connected stream.
+    pub fn from_stream(stream: UnixStream) -> Self {
+        let path = stream
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|p| p.to_path_buf()))
+            .unwrap_or_default();
+
+        Self {
+            path,
+            config: TransportConfig::default(),
+            stream: Some(stream),
+        }
+    }
+
+    /// Set connect and frame read / write timeouts.
+    pub fn with_config(mut self, config: TransportConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+#[async_trait]
+impl FlipperTransport for UnixSocketTransport {
+    /// Connect to the socket, if not connected yet.
+    async fn init(&mut self) -> Result<(), FlipperError> {
+        if self.stream.is_none() {
+            let stream = with_timeout(
+                self.config.handshake_timeout,
+                TimeoutPhase::Handshake,
+                async {
+                    UnixStream::connect(&self.path)
+                        .await
+                        .map_err(|e| -> FlipperError { FlipperError::IOFailure(e.to_string()) })
+                },
+            )
+            .await?;
+            debug!("Connected to {:?}\n", self.path);
+            self.stream = Some(stream);
+        }
+
+        Ok(())
+    }
+
+    fn into_channel(
+        self,
+    ) -> (
+        Box<dyn FlipperFrameReceiver + Send + Sync>,
+        Box<dyn FlipperFrameSender + Send + Sync>,
+    ) {
+        StreamTransport::new(self.stream.expect("Not initialized!"))
+            .with_config(self.config)
+            .into_channel()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_roundtrip_over_socketpair() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut left = UnixSocketTransport::from_stream(a);
+        let mut right = UnixSocketTransport::from_stream(b);
+        left.init().await.unwrap();
+        right.init().await.unwrap();
+
+        let (_, mut sender) = left.into_channel();
+        let (mut receiver, _) = right.into_channel();
+        sender.write_frame(&[0x08, 0x02, 0x2a, 0x00]).await.unwrap();
+        assert_eq!(
+            receiver.read_frame().await.unwrap(),
+            vec![0x08, 0x02, 0x2a, 0x00]
+        );
+    }
+}